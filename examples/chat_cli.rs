@@ -1,6 +1,9 @@
 use dotenvy::dotenv;
 use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole},
+    chat::{
+        conversation::{Conversation, TruncationStrategy},
+        ChatCompletion,
+    },
     Credentials,
 };
 use std::io::{stdin, stdout, Write};
@@ -11,11 +14,13 @@ async fn main() {
     dotenv().unwrap();
     let credentials = Credentials::from_env();
 
-    let mut messages = vec![ChatCompletionMessage {
-        role: ChatCompletionMessageRole::System,
-        content: Some("You are a large language model built into a command line interface as an example of what the `openai` Rust library made by Valentine Briese can do.".to_string()),
-        ..Default::default()
-    }];
+    // gpt-3.5-turbo has a context window of 16,385 tokens.
+    let mut conversation = Conversation::new(16_385)
+        .reserve_output(1_000)
+        .strategy(TruncationStrategy::DropOldest);
+    conversation
+        .push_system("You are a large language model built into a command line interface as an example of what the `openai` Rust library made by Valentine Briese can do.")
+        .unwrap();
 
     loop {
         print!("User: ");
@@ -24,25 +29,17 @@ async fn main() {
         let mut user_message_content = String::new();
 
         stdin().read_line(&mut user_message_content).unwrap();
-        messages.push(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(user_message_content),
-            ..Default::default()
-        });
+        conversation.push_user(user_message_content).unwrap();
 
-        let chat_completion = ChatCompletion::builder("gpt-3.5-turbo", messages.clone())
-            .credentials(credentials.clone())
-            .create()
+        let returned_message = conversation
+            .send(ChatCompletion::builder("gpt-3.5-turbo", []).credentials(credentials.clone()))
             .await
             .unwrap();
-        let returned_message = chat_completion.choices.first().unwrap().message.clone();
 
         println!(
             "{:#?}: {}",
             &returned_message.role,
-            &returned_message.content.clone().unwrap().trim()
+            &returned_message.content.clone().unwrap().to_text().trim()
         );
-
-        messages.push(returned_message);
     }
 }
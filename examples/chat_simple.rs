@@ -13,12 +13,12 @@ async fn main() {
     let messages = vec![
         ChatCompletionMessage {
             role: ChatCompletionMessageRole::System,
-            content: Some("You are a helpful assistant.".to_string()),
+            content: Some("You are a helpful assistant.".into()),
             ..Default::default()
         },
         ChatCompletionMessage {
             role: ChatCompletionMessageRole::User,
-            content: Some("Tell me a random crab fact".to_string()),
+            content: Some("Tell me a random crab fact".into()),
             ..Default::default()
         },
     ];
@@ -32,6 +32,6 @@ async fn main() {
     println!(
         "{:#?}: {}",
         returned_message.role,
-        returned_message.content.unwrap().trim()
+        returned_message.content.unwrap().to_text().trim()
     );
 }
@@ -1,11 +1,13 @@
 use dotenvy::dotenv;
-use openai::chat::{ChatCompletion, ChatCompletionDelta};
+use futures_util::StreamExt;
+use openai::chat::{
+    events::ChatCompletionEvent, ChatCompletion, ChatCompletionDelta, ChatCompletionStream,
+};
 use openai::{
-    chat::{ChatCompletionMessage, ChatCompletionMessageRole},
+    chat::conversation::{Conversation, TruncationStrategy},
     Credentials,
 };
 use std::io::{stdin, stdout, Write};
-use tokio::sync::mpsc::Receiver;
 
 #[tokio::main]
 async fn main() {
@@ -13,11 +15,13 @@ async fn main() {
     dotenv().unwrap();
     let credentials = Credentials::from_env();
 
-    let mut messages = vec![ChatCompletionMessage {
-        role: ChatCompletionMessageRole::System,
-        content: Some("You're an AI that replies to each message verbosely.".to_string()),
-        ..Default::default()
-    }];
+    // gpt-3.5-turbo has a context window of 16,385 tokens.
+    let mut conversation = Conversation::new(16_385)
+        .reserve_output(1_000)
+        .strategy(TruncationStrategy::DropOldest);
+    conversation
+        .push_system("You're an AI that replies to each message verbosely.")
+        .unwrap();
 
     loop {
         print!("User: ");
@@ -26,47 +30,36 @@ async fn main() {
         let mut user_message_content = String::new();
 
         stdin().read_line(&mut user_message_content).unwrap();
-        messages.push(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::User,
-            content: Some(user_message_content),
-            ..Default::default()
-        });
+        conversation.push_user(user_message_content).unwrap();
+        conversation.fit().await.unwrap();
 
-        let chat_stream = ChatCompletionDelta::builder("gpt-3.5-turbo", messages.clone())
-            .credentials(credentials.clone())
-            .create_stream()
-            .await
-            .unwrap();
+        let chat_stream =
+            ChatCompletionDelta::builder("gpt-3.5-turbo", conversation.messages().to_vec())
+                .credentials(credentials.clone())
+                .create_stream()
+                .await
+                .unwrap();
 
         let chat_completion: ChatCompletion = listen_for_tokens(chat_stream).await;
         let returned_message = chat_completion.choices.first().unwrap().message.clone();
 
-        messages.push(returned_message);
+        conversation.push(returned_message).unwrap();
     }
 }
 
-async fn listen_for_tokens(mut chat_stream: Receiver<ChatCompletionDelta>) -> ChatCompletion {
-    let mut merged: Option<ChatCompletionDelta> = None;
-    while let Some(delta) = chat_stream.recv().await {
-        let choice = &delta.choices[0];
-        if let Some(role) = &choice.delta.role {
-            print!("{:#?}: ", role);
-        }
-        if let Some(content) = &choice.delta.content {
-            print!("{}", content);
-        }
-        if let Some(_) = &choice.finish_reason {
+async fn listen_for_tokens(chat_stream: ChatCompletionStream) -> ChatCompletion {
+    print!("Assistant: ");
+    let mut events = chat_stream.events();
+    while let Some(event) = events.next().await {
+        match event.unwrap() {
+            ChatCompletionEvent::ContentDelta { delta, .. } => print!("{}", delta),
+            ChatCompletionEvent::RefusalDelta { delta, .. } => print!("{}", delta),
             // The message being streamed has been fully received.
-            print!("\n");
+            ChatCompletionEvent::ChoiceFinished { .. } => println!(),
+            ChatCompletionEvent::Completed(completion) => return completion,
+            _ => {}
         }
         stdout().flush().unwrap();
-        // Merge completion into accrued.
-        match merged.as_mut() {
-            Some(c) => {
-                c.merge(delta).unwrap();
-            }
-            None => merged = Some(delta),
-        };
     }
-    merged.unwrap().into()
+    panic!("The stream ended without a completion");
 }
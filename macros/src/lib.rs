@@ -0,0 +1,379 @@
+//! Procedural macros of the `openai` crate, which re-exports them.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Expr, Fields, FnArg, GenericArgument,
+    ItemFn, Lit, Meta, Pat, PathArguments, ReturnType, Token, Type,
+};
+
+/// Turns a function into a chat tool. See the documentation of `openai::tool`.
+#[proc_macro_attribute]
+pub fn tool(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let message = "`#[openai::tool]` takes no arguments";
+        return Error::new(Span::call_site(), message)
+            .to_compile_error()
+            .into();
+    }
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(function: ItemFn) -> syn::Result<TokenStream2> {
+    let signature = &function.sig;
+    if !signature.generics.params.is_empty() || signature.generics.where_clause.is_some() {
+        let message = "tools cannot be generic, their arguments need a single schema";
+        return Err(Error::new(signature.generics.span(), message));
+    }
+    if let Some(variadic) = &signature.variadic {
+        return Err(Error::new(variadic.span(), "tools cannot be variadic"));
+    }
+
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    for input in &signature.inputs {
+        let input = match input {
+            FnArg::Receiver(receiver) => {
+                let message = "tools cannot take `self`, use a free function instead";
+                return Err(Error::new(receiver.span(), message));
+            }
+            FnArg::Typed(input) => input,
+        };
+        match &*input.pat {
+            Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                names.push(pat.ident.clone());
+            }
+            pat => {
+                let message =
+                    "the parameters of tools must be plain names, which name the arguments";
+                return Err(Error::new(pat.span(), message));
+            }
+        }
+        if let Type::ImplTrait(r#type) = &*input.ty {
+            let message = "the parameters of tools must have concrete types, to be deserialized";
+            return Err(Error::new(r#type.span(), message));
+        }
+        types.push(input.ty.clone());
+    }
+
+    let (output, returns_result) = match &signature.output {
+        ReturnType::Default => (quote!(()), false),
+        ReturnType::Type(_, r#type) => match first_type_argument(r#type, "Result") {
+            Some(ok) => (quote!(#ok), true),
+            None => (quote!(#r#type), false),
+        },
+    };
+
+    let (description, parameter_docs) = split_docs(&doc_lines(&function));
+    let field_docs = names.iter().map(|name| {
+        let name = name.unraw().to_string();
+        match parameter_docs
+            .iter()
+            .find(|(parameter, _)| *parameter == name)
+        {
+            Some((_, doc)) => {
+                let doc = format!(" {doc}");
+                quote!(#[doc = #doc])
+            }
+            None => quote!(),
+        }
+    });
+
+    let vis = &function.vis;
+    let ident = &signature.ident;
+    let tool_name = ident.unraw().to_string();
+    let module_doc = format!(" The `{tool_name}` tool, generated by `#[openai::tool]`.");
+    let mut call = quote!(super::#ident(#(arguments.#names),*));
+    if signature.asyncness.is_some() {
+        call = quote!(#call.await);
+    }
+    let result = if returns_result {
+        quote!(#call.map_err(::core::convert::Into::into))
+    } else {
+        quote!(::core::result::Result::Ok(#call))
+    };
+
+    Ok(quote! {
+        #function
+
+        #[doc = #module_doc]
+        #vis mod #ident {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #(#[doc = #description])*
+            #[derive(::openai::__private::serde::Deserialize, ::openai::__private::schemars::JsonSchema)]
+            #[serde(crate = "::openai::__private::serde")]
+            #[schemars(crate = "::openai::__private::schemars", rename = #tool_name)]
+            pub struct Arguments {
+                #(#field_docs pub #names: #types,)*
+            }
+
+            /// The definition of the tool, with a non-strict schema in the OpenAI style.
+            pub fn tool() -> ::openai::chat::ChatCompletionTool {
+                ::openai::chat::ChatCompletionTool::new::<Arguments>(
+                    ::core::option::Option::None,
+                    ::openai::chat::structured_output::JsonSchemaStyle::OpenAI,
+                )
+            }
+
+            /// Calls the function with the arguments.
+            pub async fn invoke(arguments: Arguments) -> ::openai::__private::anyhow::Result<#output> {
+                #result
+            }
+
+            /// Deserializes the arguments of a tool call, converting them back from the
+            /// shape of the schema of `tool`, and calls the function.
+            pub async fn dispatch(
+                function: &::openai::chat::ToolCallFunction,
+            ) -> ::openai::__private::anyhow::Result<#output> {
+                let (definition, side_table) =
+                    ::openai::chat::structured_output::ToolCallFunctionDefinition::with_side_table::<Arguments>(
+                        ::core::option::Option::None,
+                        ::openai::chat::structured_output::JsonSchemaStyle::OpenAI,
+                    );
+                let arguments = ::openai::__private::serde_json::from_str(&function.arguments)?;
+                let arguments: Arguments = side_table
+                    .deserialize(&definition.parameters.unwrap_or_default(), arguments)?;
+                invoke(arguments).await
+            }
+
+            /// Registers the tool in a registry, to be run by a `ToolRunner`.
+            pub fn register(
+                registry: &mut ::openai::chat::tools::ToolRegistry,
+            ) -> &mut ::openai::chat::tools::ToolRegistry {
+                registry.register(invoke)
+            }
+        }
+    })
+}
+
+/// The `T` of a type named `name<T>` or `name<T, ...>`, such as `Result<T, E>`.
+fn first_type_argument<'a>(r#type: &'a Type, name: &str) -> Option<&'a Type> {
+    let Type::Path(path) = r#type else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != name {
+        return None;
+    }
+    let PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first()? {
+        GenericArgument::Type(argument) => Some(argument),
+        _ => None,
+    }
+}
+
+/// The lines of the doc comment of a function, without their leading space.
+fn doc_lines(function: &ItemFn) -> Vec<String> {
+    let mut lines = Vec::new();
+    for attr in &function.attrs {
+        let Meta::NameValue(meta) = &attr.meta else {
+            continue;
+        };
+        if !meta.path.is_ident("doc") {
+            continue;
+        }
+        if let Expr::Lit(expr) = &meta.value {
+            if let Lit::Str(doc) = &expr.lit {
+                let doc = doc.value();
+                for line in doc.lines() {
+                    lines.push(line.strip_prefix(' ').unwrap_or(line).to_string());
+                }
+            }
+        }
+    }
+    lines
+}
+
+/// Splits a doc comment into the description of the tool and the descriptions of
+/// its parameters, listed in an `# Arguments` section like
+/// ``* `name` - The description.``.
+fn split_docs(lines: &[String]) -> (Vec<String>, Vec<(String, String)>) {
+    let mut description = Vec::new();
+    let mut parameters: Vec<(String, String)> = Vec::new();
+    let mut in_arguments = false;
+    let mut in_code = false;
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            in_code = !in_code;
+        } else if !in_code && trimmed.starts_with('#') {
+            let heading = trimmed.trim_start_matches('#').trim();
+            in_arguments = heading.eq_ignore_ascii_case("arguments")
+                || heading.eq_ignore_ascii_case("parameters");
+            if in_arguments {
+                continue;
+            }
+        }
+        if !in_arguments {
+            description.push(format!(" {line}"));
+            continue;
+        }
+        let item = trimmed
+            .strip_prefix("* ")
+            .or_else(|| trimmed.strip_prefix("- "));
+        match item.and_then(parse_parameter) {
+            Some(parameter) => parameters.push(parameter),
+            // Continues the description of the previous parameter.
+            None if !trimmed.is_empty() => {
+                if let Some((_, doc)) = parameters.last_mut() {
+                    doc.push(' ');
+                    doc.push_str(trimmed);
+                }
+            }
+            None => {}
+        }
+    }
+    while description
+        .last()
+        .is_some_and(|line| line.trim().is_empty())
+    {
+        description.pop();
+    }
+    (description, parameters)
+}
+
+/// Parses ``"`name` - The description."``, also with `:` or without backticks.
+fn parse_parameter(item: &str) -> Option<(String, String)> {
+    let (name, rest) = match item.strip_prefix('`') {
+        Some(item) => item.split_once('`')?,
+        None => item.split_at(item.find(|c: char| c.is_whitespace() || c == ':')?),
+    };
+    let doc = rest
+        .trim_start()
+        .trim_start_matches(['-', ':', '–', '—'])
+        .trim();
+    let name = name.strip_prefix("r#").unwrap_or(name);
+    Some((name.to_string(), doc.to_string()))
+}
+
+/// Derives `PartialType`. See the documentation of `openai::chat::partial_json`.
+#[proc_macro_derive(PartialType, attributes(serde))]
+pub fn derive_partial_type(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match expand_partial_type(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand_partial_type(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() || input.generics.where_clause.is_some() {
+        let message = "`PartialType` cannot be derived for generic types";
+        return Err(Error::new(input.generics.span(), message));
+    }
+    let ident = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            fields => {
+                let message = "`PartialType` can only be derived for structs with named fields";
+                return Err(Error::new(fields.span(), message));
+            }
+        },
+        // An enum value is only usable once it is complete.
+        Data::Enum(_) => {
+            return Ok(quote! {
+                impl ::openai::chat::partial_json::PartialType for #ident {
+                    type Partial = Self;
+                }
+            })
+        }
+        Data::Union(data) => {
+            let message = "`PartialType` cannot be derived for unions";
+            return Err(Error::new(data.union_token.span(), message));
+        }
+    };
+
+    let container_attrs = serde_attrs(&input.attrs, &["rename_all"])?;
+    let mut partial_fields = Vec::new();
+    for field in fields {
+        if has_serde_flag(&field.attrs, &["flatten"])? {
+            let message = "flattened fields are not supported by `PartialType`";
+            return Err(Error::new(field.span(), message));
+        }
+        if has_serde_flag(&field.attrs, &["skip", "skip_deserializing"])? {
+            continue;
+        }
+        let name = &field.ident;
+        let attrs = serde_attrs(&field.attrs, &["rename", "alias"])?;
+        // `Option<T>` becomes `Option<T::Partial>` rather than `Option<Option<T::Partial>>`.
+        let inner = first_type_argument(&field.ty, "Option").unwrap_or(&field.ty);
+        partial_fields.push(quote! {
+            #(#attrs)*
+            #[serde(
+                default,
+                deserialize_with = "::openai::chat::partial_json::deserialize_lenient"
+            )]
+            pub #name: ::core::option::Option<
+                <#inner as ::openai::chat::partial_json::PartialType>::Partial
+            >,
+        });
+    }
+
+    let vis = &input.vis;
+    let partial = syn::Ident::new(&format!("Partial{}", ident.unraw()), ident.span());
+    let doc =
+        format!(" A snapshot of a streamed [`{ident}`], where every field may still be missing.");
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(
+            ::core::fmt::Debug,
+            ::core::clone::Clone,
+            ::core::default::Default,
+            ::core::cmp::PartialEq,
+            ::openai::__private::serde::Deserialize,
+        )]
+        #[serde(crate = "::openai::__private::serde")]
+        #(#container_attrs)*
+        #vis struct #partial {
+            #(#partial_fields)*
+        }
+
+        impl ::openai::chat::partial_json::PartialType for #ident {
+            type Partial = #partial;
+        }
+    })
+}
+
+/// The `#[serde(...)]` attributes reduced to the `allowed` options, which the
+/// partial type needs to read the same JSON.
+fn serde_attrs(attrs: &[Attribute], allowed: &[&str]) -> syn::Result<Vec<TokenStream2>> {
+    let mut kept = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        let metas = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+        let metas: Vec<&Meta> = metas
+            .iter()
+            .filter(|meta| allowed.iter().any(|name| meta.path().is_ident(name)))
+            .collect();
+        if !metas.is_empty() {
+            kept.push(quote!(#[serde(#(#metas),*)]));
+        }
+    }
+    Ok(kept)
+}
+
+/// Whether a `#[serde(...)]` attribute sets one of the `flags`.
+fn has_serde_flag(attrs: &[Attribute], flags: &[&str]) -> syn::Result<bool> {
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        let metas = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+        if metas
+            .iter()
+            .any(|meta| flags.iter().any(|flag| meta.path().is_ident(flag)))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
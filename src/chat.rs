@@ -1,20 +1,31 @@
 //! Given a chat conversation, the model will return a chat completion response.
+pub mod audio;
+pub mod conversation;
+pub mod dynamic_schema;
+pub mod events;
+pub mod logprobs;
+pub mod partial_json;
+pub mod schema_lint;
+pub mod stored;
 pub mod structured_output;
+pub mod tools;
+pub mod validator;
 
-use super::{openai_post, ApiResponseOrError, Credentials, Usage};
-use crate::openai_request_stream;
+use super::{openai_post, ApiResponseOrError, Credentials, StreamError, Usage};
+use crate::{openai_request_stream, OpenAiError, StreamRetryPolicy};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use derive_builder::Builder;
-use futures_util::StreamExt;
+use futures_util::stream::{BoxStream, Stream};
 use reqwest::Method;
-use reqwest_eventsource::{CannotCloneRequestError, Event, EventSource};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use structured_output::{
     ChatCompletionResponseFormatJsonSchema, JsonSchemaStyle, ToolCallFunctionDefinition,
 };
-use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 /// A full chat completion.
 pub type ChatCompletion = ChatCompletionGeneric<ChatCompletionChoice>;
@@ -30,20 +41,29 @@ pub struct ChatCompletionGeneric<C> {
     pub model: String,
     pub choices: Vec<C>,
     pub usage: Option<Usage>,
+    /// The backend configuration the model ran with. Together with `seed`, a change of
+    /// fingerprint explains why the same request may produce different results.
+    pub system_fingerprint: Option<String>,
+    /// The processing tier actually used to serve the request, e.g. `default` or `flex`.
+    pub service_tier: Option<String>,
 }
 
-#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct ChatCompletionChoice {
     pub index: u64,
     pub finish_reason: String,
     pub message: ChatCompletionMessage,
+    /// The log probabilities of the generated tokens, if requested with `logprobs`.
+    pub logprobs: Option<ChatCompletionLogprobs>,
 }
 
-#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct ChatCompletionChoiceDelta {
     pub index: u64,
     pub finish_reason: Option<String>,
     pub delta: ChatCompletionMessageDelta,
+    /// The log probabilities of the tokens of this chunk, if requested with `logprobs`.
+    pub logprobs: Option<ChatCompletionLogprobs>,
 }
 
 fn is_none_or_empty_vec<T>(opt: &Option<Vec<T>>) -> bool {
@@ -54,11 +74,16 @@ fn is_none_or_empty_vec<T>(opt: &Option<Vec<T>>) -> bool {
 pub struct ChatCompletionMessage {
     /// The role of the author of this message.
     pub role: ChatCompletionMessageRole,
-    /// The contents of the message
+    /// The contents of the message, either plain text or a list of content parts
+    /// (text, images, audio and files).
     ///
     /// This is always required for all messages, except for when ChatGPT calls
     /// a function.
-    pub content: Option<String>,
+    pub content: Option<ChatCompletionContent>,
+    /// The refusal message generated by the model, instead of `content`, when it
+    /// declines to answer a structured output request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
     /// The name of the user in a multi-user chat
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
@@ -77,6 +102,18 @@ pub struct ChatCompletionMessage {
     /// otherwise it should be empty.
     #[serde(skip_serializing_if = "is_none_or_empty_vec")]
     pub tool_calls: Option<Vec<ToolCall>>,
+    /// Annotations of the content, such as the citations of a web search.
+    /// Returned by the API only, and never sent back.
+    #[serde(default, skip_serializing)]
+    pub annotations: Option<Vec<ChatCompletionAnnotation>>,
+    /// The audio response of the model, if audio output was requested.
+    /// Only its `id` is sent back, to refer to the audio in later turns.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "audio::serialize_audio_reference"
+    )]
+    pub audio: Option<ChatCompletionAudio>,
 }
 
 /// Same as ChatCompletionMessage, but received during a response stream.
@@ -86,6 +123,8 @@ pub struct ChatCompletionMessageDelta {
     pub role: Option<ChatCompletionMessageRole>,
     /// The contents of the message
     pub content: Option<String>,
+    /// The refusal message generated by the model.
+    pub refusal: Option<String>,
     /// The name of the user in a multi-user chat
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
@@ -104,6 +143,271 @@ pub struct ChatCompletionMessageDelta {
     /// otherwise it should be empty.
     #[serde(skip_serializing_if = "is_none_or_empty_vec")]
     pub tool_calls: Option<Vec<ToolCallDelta>>,
+    /// Annotations of the content, such as the citations of a web search.
+    pub annotations: Option<Vec<ChatCompletionAnnotation>>,
+    /// A fragment of the audio response of the model.
+    pub audio: Option<ChatCompletionAudioDelta>,
+}
+
+/// Log probability information of a choice.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Default)]
+pub struct ChatCompletionLogprobs {
+    /// The log probabilities of the content tokens.
+    pub content: Option<Vec<ChatCompletionTokenLogprob>>,
+    /// The log probabilities of the refusal tokens.
+    pub refusal: Option<Vec<ChatCompletionTokenLogprob>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ChatCompletionTokenLogprob {
+    pub token: String,
+    /// The log probability of this token, or `-9999.0` if it is very unlikely.
+    pub logprob: f64,
+    /// The UTF-8 bytes of the token. A character may be split across several tokens,
+    /// whose bytes must be joined to decode it.
+    pub bytes: Option<Vec<u8>>,
+    /// The most likely tokens at this position, if requested with `top_logprobs`.
+    #[serde(default)]
+    pub top_logprobs: Vec<ChatCompletionTopLogprob>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ChatCompletionTopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    pub bytes: Option<Vec<u8>>,
+}
+
+/// An annotation of the message content.
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionAnnotation {
+    /// A citation of a web page, when using web search.
+    UrlCitation {
+        url_citation: ChatCompletionUrlCitation,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct ChatCompletionUrlCitation {
+    /// The index of the first character of the citation in the content.
+    pub start_index: u32,
+    /// The index of the last character of the citation in the content.
+    pub end_index: u32,
+    pub url: String,
+    pub title: String,
+}
+
+/// The audio response of the model.
+///
+/// Messages only serialize the `id` of their audio, so the other fields are empty
+/// when reading such a message back, e.g. from a saved conversation.
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq, Default)]
+pub struct ChatCompletionAudio {
+    /// The identifier used to refer to this audio in follow-up requests.
+    pub id: String,
+    /// The base64 encoded audio bytes, in the requested format.
+    #[serde(default)]
+    pub data: String,
+    /// The transcript of the audio.
+    #[serde(default)]
+    pub transcript: String,
+    /// The Unix timestamp after which the audio can no longer be referred to.
+    #[serde(default)]
+    pub expires_at: u64,
+}
+
+/// Same as ChatCompletionAudio, but received during a response stream.
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq, Default)]
+pub struct ChatCompletionAudioDelta {
+    pub id: Option<String>,
+    pub data: Option<String>,
+    pub transcript: Option<String>,
+    pub expires_at: Option<u64>,
+}
+
+/// The contents of a message.
+///
+/// Serializes as a plain string for [`ChatCompletionContent::Text`], which is what
+/// every model accepts, and as an array of typed parts otherwise.
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum ChatCompletionContent {
+    Text(String),
+    Parts(Vec<ChatCompletionContentPart>),
+}
+
+impl ChatCompletionContent {
+    /// Returns the text if the content is a plain string.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            ChatCompletionContent::Text(text) => Some(text),
+            ChatCompletionContent::Parts(_) => None,
+        }
+    }
+
+    /// Returns the text of the content, concatenating all text parts.
+    pub fn to_text(&self) -> String {
+        match self {
+            ChatCompletionContent::Text(text) => text.clone(),
+            ChatCompletionContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatCompletionContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for ChatCompletionContent {
+    fn from(text: String) -> Self {
+        ChatCompletionContent::Text(text)
+    }
+}
+
+impl From<&str> for ChatCompletionContent {
+    fn from(text: &str) -> Self {
+        ChatCompletionContent::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ChatCompletionContentPart>> for ChatCompletionContent {
+    fn from(parts: Vec<ChatCompletionContentPart>) -> Self {
+        ChatCompletionContent::Parts(parts)
+    }
+}
+
+/// A single part of a multimodal message.
+///
+/// [API Reference](https://platform.openai.com/docs/api-reference/chat/create#chat-create-messages)
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        image_url: ChatCompletionImageUrl,
+    },
+    InputAudio {
+        input_audio: ChatCompletionInputAudio,
+    },
+    File {
+        file: ChatCompletionInputFile,
+    },
+}
+
+impl ChatCompletionContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        ChatCompletionContentPart::Text { text: text.into() }
+    }
+
+    /// An image referenced by URL, which may also be a `data:` URL.
+    pub fn image_url(url: impl Into<String>, detail: Option<ChatCompletionImageDetail>) -> Self {
+        ChatCompletionContentPart::ImageUrl {
+            image_url: ChatCompletionImageUrl {
+                url: url.into(),
+                detail,
+            },
+        }
+    }
+
+    /// An image sent inline as a base64 `data:` URL, e.g. with a `media_type` of `image/png`.
+    pub fn image_data(
+        media_type: &str,
+        bytes: impl AsRef<[u8]>,
+        detail: Option<ChatCompletionImageDetail>,
+    ) -> Self {
+        let url = format!("data:{media_type};base64,{}", BASE64_STANDARD.encode(bytes));
+        Self::image_url(url, detail)
+    }
+
+    /// Audio sent inline, encoded as base64.
+    pub fn input_audio(bytes: impl AsRef<[u8]>, format: ChatCompletionInputAudioFormat) -> Self {
+        ChatCompletionContentPart::InputAudio {
+            input_audio: ChatCompletionInputAudio {
+                data: BASE64_STANDARD.encode(bytes),
+                format,
+            },
+        }
+    }
+
+    /// A file previously uploaded through the [files](crate::files) API.
+    pub fn file_id(file_id: impl Into<String>) -> Self {
+        ChatCompletionContentPart::File {
+            file: ChatCompletionInputFile {
+                file_id: Some(file_id.into()),
+                file_data: None,
+                filename: None,
+            },
+        }
+    }
+
+    /// A file sent inline as a base64 `data:` URL, e.g. with a `media_type` of `application/pdf`.
+    pub fn file_data(
+        filename: impl Into<String>,
+        media_type: &str,
+        bytes: impl AsRef<[u8]>,
+    ) -> Self {
+        ChatCompletionContentPart::File {
+            file: ChatCompletionInputFile {
+                file_id: None,
+                file_data: Some(format!(
+                    "data:{media_type};base64,{}",
+                    BASE64_STANDARD.encode(bytes)
+                )),
+                filename: Some(filename.into()),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ChatCompletionImageUrl {
+    /// Either a URL of the image or the base64 encoded image data as a `data:` URL.
+    pub url: String,
+    /// Specifies the detail level of the image.
+    /// [Learn more](https://platform.openai.com/docs/guides/vision#low-or-high-fidelity-image-understanding).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ChatCompletionImageDetail>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatCompletionImageDetail {
+    Auto,
+    Low,
+    High,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ChatCompletionInputAudio {
+    /// Base64 encoded audio data.
+    pub data: String,
+    /// The format of the encoded audio data.
+    pub format: ChatCompletionInputAudioFormat,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatCompletionInputAudioFormat {
+    Wav,
+    Mp3,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct ChatCompletionInputFile {
+    /// The ID of an uploaded file to use as input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    /// The base64 encoded file data, used when passing the file to the model as a string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<String>,
+    /// The name of the file, used when passing the file to the model as a string.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
@@ -115,8 +419,8 @@ pub enum ChatCompletionTool {
 }
 
 impl ChatCompletionTool {
-    pub fn new<T: JsonSchema>(strict: Option<bool>) -> Self {
-        let function = ToolCallFunctionDefinition::new::<T>(strict);
+    pub fn new<T: JsonSchema>(strict: Option<bool>, json_style: JsonSchemaStyle) -> Self {
+        let function = ToolCallFunctionDefinition::new::<T>(strict, json_style);
         ChatCompletionTool::Function { function }
     }
 }
@@ -186,7 +490,7 @@ pub struct ToolCallDelta {
     /// The type of the tool. Currently, only `function` is supported.
     pub r#type: Option<FunctionType>,
     /// The function that the model called.
-    pub function: Option<ToolCallFunction>,
+    pub function: Option<ToolCallFunctionDelta>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
@@ -201,6 +505,17 @@ pub struct ToolCallFunction {
     pub arguments: String,
 }
 
+/// Same as ToolCallFunction, but received during a response stream.
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq, Default)]
+pub struct ToolCallFunctionDelta {
+    /// The name of the function to call. Only sent with the first fragment of a tool call.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// A fragment of the arguments to call the function with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
 pub struct ChatCompletionFunctionDefinition {
     /// The name of the function
@@ -234,10 +549,11 @@ pub struct ChatCompletionFunctionCallDelta {
     pub arguments: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum ChatCompletionMessageRole {
     System,
+    #[default]
     User,
     Assistant,
     Function,
@@ -258,6 +574,7 @@ pub enum ChatCompletionReasoningEffort {
 #[builder(pattern = "owned")]
 #[builder(name = "ChatCompletionBuilder")]
 #[builder(setter(strip_option, into))]
+#[builder(build_fn(validate = "Self::validate"))]
 pub struct ChatCompletionRequest {
     /// ID of the model to use. Currently, only `gpt-3.5-turbo`, `gpt-3.5-turbo-0301` and `gpt-4`
     /// are supported.
@@ -286,9 +603,22 @@ pub struct ChatCompletionRequest {
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     n: Option<u8>,
+    /// The types of output the model should generate, `[Text]` by default.
+    /// Models supporting audio output can also generate `[Text, Audio]`.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modalities: Option<Vec<ChatCompletionModality>>,
+    /// The voice and format of the audio output. Required with the `Audio` modality.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio: Option<ChatCompletionAudioParameters>,
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
+    /// Options for streaming responses. Only set this when streaming.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<ChatCompletionStreamOptions>,
     /// Up to 4 sequences where the API will stop generating further tokens.
     #[builder(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -325,10 +655,30 @@ pub struct ChatCompletionRequest {
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     logit_bias: Option<HashMap<String, f32>>,
+    /// Whether to return the log probabilities of the output tokens in the `logprobs`
+    /// of each choice. See [`ChatCompletionLogprobs`] for helpers to analyse them.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    /// The number of most likely tokens, between 0 and 20, to return at each token
+    /// position along with their log probabilities. Requires `logprobs` to be true.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u8>,
     /// A unique identifier representing your end-user, which can help OpenAI to monitor and detect abuse. [Learn more](https://platform.openai.com/docs/guides/safety-best-practices/end-user-ids).
     #[builder(default)]
     #[serde(skip_serializing_if = "String::is_empty")]
     user: String,
+    /// Whether to store the output of this request, for model distillation or evals.
+    /// Stored completions can be listed and retrieved with the [`stored`] API.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    store: Option<bool>,
+    /// Up to 16 key-value pairs attached to a stored completion, to filter it when listing.
+    /// Keys are at most 64 characters long and values at most 512.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<HashMap<String, String>>,
     /// A list of tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of functions the model may generate JSON inputs for. A max of 128 functions are supported.
     #[builder(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -381,6 +731,18 @@ pub struct ChatCompletionRequest {
     #[serde(skip_serializing)]
     #[builder(default)]
     credentials: Option<Credentials>,
+    /// Whether to retry a streamed request that failed before its first chunk.
+    /// Streams are never retried by default.
+    #[serde(skip_serializing)]
+    #[builder(default)]
+    stream_retry_policy: Option<StreamRetryPolicy>,
+    /// Whether building the request checks its schemas with `strict: true` against
+    /// the limits of OpenAI strict mode, see [`schema_lint`]. Off by default, as other
+    /// providers support other schemas.
+    #[serde(skip_serializing)]
+    #[builder(default)]
+    #[allow(dead_code)] // Only read by the builder, when validating.
+    lint_strict_schemas: Option<bool>,
     /// Parameters unique to the Venice API.
     /// https://docs.venice.ai/api-reference/api-spec
     #[builder(default)]
@@ -388,6 +750,62 @@ pub struct ChatCompletionRequest {
     venice_parameters: Option<VeniceParameters>,
 }
 
+#[derive(Serialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ChatCompletionStreamOptions {
+    /// If set, an additional chunk is streamed before the `[DONE]` message. Its `usage`
+    /// field holds the token usage of the entire request, and its `choices` are empty.
+    /// All other chunks have a `null` usage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_usage: Option<bool>,
+    /// Whether to pad the chunks with random characters in an `obfuscation` field,
+    /// to normalize payload sizes as a mitigation against side-channel attacks.
+    /// Defaults to true; disable it to save bandwidth on trusted networks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_obfuscation: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatCompletionModality {
+    Text,
+    Audio,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ChatCompletionAudioParameters {
+    pub voice: ChatCompletionVoice,
+    pub format: ChatCompletionAudioFormat,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatCompletionVoice {
+    Alloy,
+    Ash,
+    Ballad,
+    Coral,
+    Echo,
+    Fable,
+    Nova,
+    Onyx,
+    Sage,
+    Shimmer,
+    Verse,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatCompletionAudioFormat {
+    Wav,
+    Mp3,
+    Flac,
+    Opus,
+    Aac,
+    /// Raw 16-bit little-endian mono samples at 24kHz. The only format supported
+    /// when streaming.
+    Pcm16,
+}
+
 #[derive(Serialize, Debug, Clone, Eq, PartialEq)]
 pub struct VeniceParameters {
     pub include_venice_system_prompt: bool,
@@ -427,6 +845,20 @@ impl<C> ChatCompletionGeneric<C> {
     }
 }
 
+#[cfg(feature = "tokenizer")]
+impl ChatCompletionRequest {
+    /// The number of prompt tokens of this request: its messages, tool definitions
+    /// and response format schema.
+    pub fn count_tokens(&self, tokenizer: &crate::tokenizer::Tokenizer) -> usize {
+        tokenizer.count_messages(&self.messages)
+            + tokenizer.count_tools(&self.tools)
+            + self
+                .response_format
+                .as_ref()
+                .map_or(0, |format| tokenizer.count_response_format(format))
+    }
+}
+
 impl ChatCompletion {
     pub async fn create(request: ChatCompletionRequest) -> ApiResponseOrError<Self> {
         let credentials_opt = request.credentials.clone();
@@ -437,36 +869,64 @@ impl ChatCompletion {
 impl ChatCompletionDelta {
     pub async fn create(
         request: ChatCompletionRequest,
-    ) -> Result<Receiver<Self>, CannotCloneRequestError> {
+    ) -> Result<ChatCompletionStream, StreamError> {
         let credentials_opt = request.credentials.clone();
-        let stream = openai_request_stream(
+        let inner = openai_request_stream(
             Method::POST,
             "chat/completions",
             |r| r.json(&request),
             credentials_opt,
+            request.stream_retry_policy.unwrap_or_default(),
         )
         .await?;
-        let (tx, rx) = channel::<Self>(32);
-        tokio::spawn(forward_deserialized_chat_response_stream(stream, tx));
-        Ok(rx)
+        Ok(ChatCompletionStream {
+            inner,
+            partial: None,
+            merge_error: None,
+        })
     }
 
     /// Merges the input delta completion into `self`.
     pub fn merge(
         &mut self,
         other: ChatCompletionDelta,
+    ) -> Result<(), ChatCompletionDeltaMergeError> {
+        self.merge_ref(&other)
+    }
+
+    /// Merges the input delta completion into `self`, only cloning what is new.
+    fn merge_ref(
+        &mut self,
+        other: &ChatCompletionDelta,
     ) -> Result<(), ChatCompletionDeltaMergeError> {
         if other.id.ne(&self.id) {
             return Err(ChatCompletionDeltaMergeError::DifferentCompletionIds);
         }
-        for other_choice in other.choices.iter() {
-            for choice in self.choices.iter_mut() {
-                if choice.index != other_choice.index {
-                    continue;
+        for other_choice in &other.choices {
+            match self
+                .choices
+                .iter_mut()
+                .find(|choice| choice.index == other_choice.index)
+            {
+                Some(choice) => choice.merge(other_choice)?,
+                None => {
+                    // The first chunk of a choice can arrive after chunks of other choices.
+                    let position = self
+                        .choices
+                        .partition_point(|choice| choice.index < other_choice.index);
+                    self.choices.insert(position, other_choice.clone());
                 }
-                choice.merge(other_choice)?;
             }
         }
+        if other.usage.is_some() {
+            self.usage = other.usage;
+        }
+        if other.system_fingerprint.is_some() {
+            self.system_fingerprint = other.system_fingerprint.clone();
+        }
+        if other.service_tier.is_some() {
+            self.service_tier = other.service_tier.clone();
+        }
         Ok(())
     }
 }
@@ -492,63 +952,158 @@ impl ChatCompletionChoiceDelta {
             }
         }
         // Merge contents.
-        match self.delta.content.as_mut() {
-            Some(content) => {
-                match &other.delta.content {
-                    Some(other_content) => {
-                        // Push other content into this one.
-                        content.push_str(other_content)
-                    }
-                    None => {}
-                }
+        if let Some(other_content) = &other.delta.content {
+            match self.delta.content.as_mut() {
+                // Push other content into this one.
+                Some(content) => content.push_str(other_content),
+                // Set this content to other content.
+                None => self.delta.content = Some(other_content.clone()),
             }
-            None => {
-                match &other.delta.content {
-                    Some(other_content) => {
-                        // Set this content to other content.
-                        self.delta.content = Some(other_content.clone());
-                    }
-                    None => {}
+        }
+        if let Some(other_refusal) = &other.delta.refusal {
+            self.delta
+                .refusal
+                .get_or_insert_with(String::new)
+                .push_str(other_refusal);
+        }
+        if let Some(other_annotations) = &other.delta.annotations {
+            self.delta
+                .annotations
+                .get_or_insert_with(Vec::new)
+                .extend(other_annotations.iter().cloned());
+        }
+        if let Some(other_audio) = &other.delta.audio {
+            let audio = self.delta.audio.get_or_insert_with(Default::default);
+            audio.merge(other_audio);
+        }
+        if let Some(other_logprobs) = &other.logprobs {
+            let logprobs = self.logprobs.get_or_insert_with(Default::default);
+            for (tokens, other_tokens) in [
+                (&mut logprobs.content, &other_logprobs.content),
+                (&mut logprobs.refusal, &other_logprobs.refusal),
+            ] {
+                if let Some(other_tokens) = other_tokens {
+                    tokens
+                        .get_or_insert_with(Vec::new)
+                        .extend(other_tokens.iter().cloned());
                 }
             }
-        };
+        }
 
         // merge function calls
         // function call names are concatenated
         // arguments are merged by concatenating them
         #[allow(deprecated)]
-        match self.delta.function_call.as_mut() {
-            Some(function_call) => {
-                match &other.delta.function_call {
-                    Some(other_function_call) => {
-                        // push the arguments string of the other function call into this one
-                        match (&mut function_call.arguments, &other_function_call.arguments) {
-                            (Some(function_call), Some(other_function_call)) => {
-                                function_call.push_str(&other_function_call);
-                            }
-                            (None, Some(other_function_call)) => {
-                                function_call.arguments = Some(other_function_call.clone());
-                            }
-                            _ => {}
+        if let Some(other_function_call) = &other.delta.function_call {
+            match self.delta.function_call.as_mut() {
+                Some(function_call) => {
+                    // push the arguments string of the other function call into this one
+                    match (&mut function_call.arguments, &other_function_call.arguments) {
+                        (Some(arguments), Some(other_arguments)) => {
+                            arguments.push_str(other_arguments);
                         }
+                        (None, Some(other_arguments)) => {
+                            function_call.arguments = Some(other_arguments.clone());
+                        }
+                        _ => {}
                     }
-                    None => {}
                 }
+                // Set this function call to the other function call.
+                None => self.delta.function_call = Some(other_function_call.clone()),
             }
-            None => {
-                match &other.delta.function_call {
-                    Some(other_function_call) => {
-                        // Set this content to other content.
-                        self.delta.function_call = Some(other_function_call.clone());
-                    }
-                    None => {}
+        }
+
+        // Merge tool calls by their index. The first fragment of a tool call
+        // carries its id, type and name, the following ones extend the arguments.
+        if let Some(other_tool_calls) = &other.delta.tool_calls {
+            let tool_calls = self.delta.tool_calls.get_or_insert_with(Vec::new);
+            for other_tool_call in other_tool_calls {
+                match tool_calls
+                    .iter_mut()
+                    .find(|tool_call| tool_call.index == other_tool_call.index)
+                {
+                    Some(tool_call) => tool_call.merge(other_tool_call),
+                    None => tool_calls.push(other_tool_call.clone()),
                 }
             }
-        };
+        }
+
+        if other.finish_reason.is_some() {
+            self.finish_reason = other.finish_reason.clone();
+        }
         Ok(())
     }
 }
 
+impl ToolCallDelta {
+    /// Merges the input tool call fragment into `self`.
+    pub fn merge(&mut self, other: &ToolCallDelta) {
+        if self.id.is_none() {
+            self.id = other.id.clone();
+        }
+        if self.r#type.is_none() {
+            self.r#type = other.r#type.clone();
+        }
+        if let Some(other_function) = &other.function {
+            let function = self.function.get_or_insert_with(Default::default);
+            if function.name.is_none() {
+                function.name = other_function.name.clone();
+            }
+            if let Some(other_arguments) = &other_function.arguments {
+                function
+                    .arguments
+                    .get_or_insert_with(String::new)
+                    .push_str(other_arguments);
+            }
+        }
+    }
+}
+
+impl ChatCompletionAudioDelta {
+    /// Merges the input audio fragment into `self`, concatenating data and transcript.
+    pub fn merge(&mut self, other: &ChatCompletionAudioDelta) {
+        if self.id.is_none() {
+            self.id = other.id.clone();
+        }
+        if other.expires_at.is_some() {
+            self.expires_at = other.expires_at;
+        }
+        for (text, other_text) in [
+            (&mut self.data, &other.data),
+            (&mut self.transcript, &other.transcript),
+        ] {
+            if let Some(other_text) = other_text {
+                text.get_or_insert_with(String::new).push_str(other_text);
+            }
+        }
+    }
+}
+
+impl From<ChatCompletionAudioDelta> for ChatCompletionAudio {
+    fn from(delta: ChatCompletionAudioDelta) -> Self {
+        ChatCompletionAudio {
+            id: delta.id.unwrap_or_default(),
+            data: delta.data.unwrap_or_default(),
+            transcript: delta.transcript.unwrap_or_default(),
+            expires_at: delta.expires_at.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ToolCallDelta> for ToolCall {
+    fn from(delta: ToolCallDelta) -> Self {
+        let function = delta.function.unwrap_or_default();
+        ToolCall {
+            id: delta.id.unwrap_or_default(),
+            r#type: delta.r#type.unwrap_or(FunctionType::Function),
+            function: ToolCallFunction {
+                name: function.name.unwrap_or_default(),
+                arguments: function.arguments.unwrap_or_default(),
+            },
+        }
+    }
+}
+
 impl From<ChatCompletionDelta> for ChatCompletion {
     fn from(delta: ChatCompletionDelta) -> Self {
         #[allow(deprecated)]
@@ -558,9 +1113,11 @@ impl From<ChatCompletionDelta> for ChatCompletion {
             created: delta.created,
             model: delta.model,
             usage: delta.usage,
+            system_fingerprint: delta.system_fingerprint,
+            service_tier: delta.service_tier,
             choices: delta
                 .choices
-                .iter()
+                .into_iter()
                 .map(|choice| ChatCompletionChoice {
                     index: choice.index,
                     finish_reason: clone_default_unwrapped_option_string(&choice.finish_reason),
@@ -568,13 +1125,24 @@ impl From<ChatCompletionDelta> for ChatCompletion {
                         role: choice
                             .delta
                             .role
-                            .unwrap_or_else(|| ChatCompletionMessageRole::System),
-                        content: choice.delta.content.clone(),
-                        name: choice.delta.name.clone(),
-                        function_call: choice.delta.function_call.clone().map(|f| f.into()),
+                            .unwrap_or(ChatCompletionMessageRole::Assistant),
+                        content: choice.delta.content.map(ChatCompletionContent::Text),
+                        refusal: choice.delta.refusal,
+                        name: choice.delta.name,
+                        function_call: choice.delta.function_call.map(|f| f.into()),
                         tool_call_id: None,
-                        tool_calls: Some(Vec::new()),
+                        tool_calls: choice
+                            .delta
+                            .tool_calls
+                            .filter(|tool_calls| !tool_calls.is_empty())
+                            .map(|mut tool_calls| {
+                                tool_calls.sort_by_key(|tool_call| tool_call.index);
+                                tool_calls.into_iter().map(ToolCall::from).collect()
+                            }),
+                        annotations: choice.delta.annotations,
+                        audio: choice.delta.audio.map(ChatCompletionAudio::from),
                     },
+                    logprobs: choice.logprobs,
                 })
                 .collect(),
         }
@@ -615,33 +1183,123 @@ impl std::fmt::Display for ChatCompletionDeltaMergeError {
 
 impl std::error::Error for ChatCompletionDeltaMergeError {}
 
-async fn forward_deserialized_chat_response_stream(
-    mut stream: EventSource,
-    tx: Sender<ChatCompletionDelta>,
-) -> anyhow::Result<()> {
-    while let Some(event) = stream.next().await {
-        let event = event?;
-        match event {
-            Event::Message(event) => {
-                let completion = serde_json::from_str::<ChatCompletionDelta>(&event.data)?;
-                tx.send(completion).await?;
+/// A stream of chat completion deltas.
+///
+/// The stream ends once the API sends its `[DONE]` message, or right after yielding
+/// an error. Dropping it aborts the request.
+pub struct ChatCompletionStream {
+    inner: BoxStream<'static, Result<ChatCompletionDelta, StreamError>>,
+    partial: Option<ChatCompletionDelta>,
+    merge_error: Option<ChatCompletionDeltaMergeError>,
+}
+
+impl ChatCompletionStream {
+    /// All deltas received so far, merged into one.
+    ///
+    /// After a [`StreamError::Interrupted`] this is the partial result, which can be
+    /// used to resume the conversation instead of regenerating it. It is `None` once
+    /// a delta could not be merged, see [`ChatCompletionStream::merge_error`].
+    pub fn partial(&self) -> Option<&ChatCompletionDelta> {
+        self.partial.as_ref()
+    }
+
+    /// Consumes the stream, returning all deltas received so far merged into one.
+    pub fn into_partial(self) -> Option<ChatCompletionDelta> {
+        self.partial
+    }
+
+    /// Why the deltas stopped being merged into the partial result, if they did.
+    ///
+    /// The deltas are still yielded, only the partial result is dropped.
+    pub fn merge_error(&self) -> Option<&ChatCompletionDeltaMergeError> {
+        self.merge_error.as_ref()
+    }
+}
+
+impl Stream for ChatCompletionStream {
+    type Item = Result<ChatCompletionDelta, StreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(delta))) = &poll {
+            if self.merge_error.is_none() {
+                match self.partial.as_mut() {
+                    Some(partial) => {
+                        if let Err(error) = partial.merge_ref(delta) {
+                            self.partial = None;
+                            self.merge_error = Some(error);
+                        }
+                    }
+                    None => self.partial = Some(delta.clone()),
+                }
             }
-            _ => {}
         }
+        poll
+    }
+}
+
+impl std::fmt::Debug for ChatCompletionStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChatCompletionStream")
+            .finish_non_exhaustive()
     }
-    Ok(())
 }
 
 impl ChatCompletionBuilder {
     pub async fn create(self) -> ApiResponseOrError<ChatCompletion> {
-        ChatCompletion::create(self.build().unwrap()).await
+        ChatCompletion::create(self.build()?).await
     }
 
-    pub async fn create_stream(
-        mut self,
-    ) -> Result<Receiver<ChatCompletionDelta>, CannotCloneRequestError> {
+    pub async fn create_stream(mut self) -> Result<ChatCompletionStream, StreamError> {
         self.stream = Some(Some(true));
-        ChatCompletionDelta::create(self.build().unwrap()).await
+        let request = self.build().map_err(OpenAiError::from)?;
+        ChatCompletionDelta::create(request).await
+    }
+
+    /// Lints the schemas with `strict: true` against the limits of strict mode, if
+    /// enabled with `lint_strict_schemas`.
+    fn validate(&self) -> Result<(), String> {
+        if self.lint_strict_schemas != Some(Some(true)) {
+            return Ok(());
+        }
+        let mut diagnostics = Vec::new();
+        if let Some(Some(ChatCompletionResponseFormat::JsonSchema { json_schema })) =
+            &self.response_format
+        {
+            if json_schema.strict == Some(true) {
+                diagnostics.extend(
+                    json_schema
+                        .lint()
+                        .into_iter()
+                        .map(|d| format!("/response_format/json_schema{d}")),
+                );
+            }
+        }
+        for (index, tool) in self.tools.iter().flatten().enumerate() {
+            let ChatCompletionTool::Function { function } = tool;
+            if function.strict == Some(true) {
+                diagnostics.extend(
+                    function
+                        .lint()
+                        .into_iter()
+                        .map(|d| format!("/tools/{index}/function{d}")),
+                );
+            }
+        }
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid strict schema:\n{}",
+                diagnostics.join("\n")
+            ))
+        }
+    }
+}
+
+impl From<ChatCompletionBuilderError> for OpenAiError {
+    fn from(value: ChatCompletionBuilderError) -> Self {
+        OpenAiError::new(value.to_string(), "invalid_request_error".to_string())
     }
 }
 
@@ -652,17 +1310,13 @@ fn clone_default_unwrapped_option_string(string: &Option<String>) -> String {
     }
 }
 
-impl Default for ChatCompletionMessageRole {
-    fn default() -> Self {
-        Self::User
-    }
-}
-
 #[cfg(test)]
 #[allow(deprecated)]
 mod tests {
     use super::*;
+    use crate::tests::{serve_responses, sse_response};
     use dotenvy::dotenv;
+    use futures_util::StreamExt;
 
     #[tokio::test]
     async fn chat() {
@@ -673,11 +1327,12 @@ mod tests {
             "gpt-3.5-turbo",
             [ChatCompletionMessage {
                 role: ChatCompletionMessageRole::User,
-                content: Some("Hello!".to_string()),
+                content: Some("Hello!".into()),
                 name: None,
                 function_call: None,
                 tool_call_id: None,
                 tool_calls: Some(Vec::new()),
+                ..Default::default()
             }],
         )
         .temperature(0.0)
@@ -695,7 +1350,8 @@ mod tests {
                 .message
                 .content
                 .as_ref()
-                .unwrap(),
+                .unwrap()
+                .to_text(),
             "Hello! How can I assist you today?"
         );
     }
@@ -712,13 +1368,13 @@ mod tests {
             [ChatCompletionMessage {
                 role: ChatCompletionMessageRole::User,
                 content: Some(
-                    "What type of seed does Mr. England sow in the song? Reply with 1 word."
-                        .to_string(),
+                    "What type of seed does Mr. England sow in the song? Reply with 1 word.".into(),
                 ),
                 name: None,
                 function_call: None,
                 tool_call_id: None,
                 tool_calls: Some(Vec::new()),
+                ..Default::default()
             }],
         )
         // Determinism currently comes from temperature 0, not seed.
@@ -737,7 +1393,8 @@ mod tests {
                 .message
                 .content
                 .as_ref()
-                .unwrap(),
+                .unwrap()
+                .to_text(),
             "Love"
         );
     }
@@ -751,11 +1408,12 @@ mod tests {
             "gpt-3.5-turbo",
             [ChatCompletionMessage {
                 role: ChatCompletionMessageRole::User,
-                content: Some("Hello!".to_string()),
+                content: Some("Hello!".into()),
                 name: None,
                 function_call: None,
                 tool_call_id: None,
                 tool_calls: Some(Vec::new()),
+                ..Default::default()
             }],
         )
         .temperature(0.0)
@@ -774,7 +1432,8 @@ mod tests {
                 .message
                 .content
                 .as_ref()
-                .unwrap(),
+                .unwrap()
+                .to_text(),
             "Hello! How can I assist you today?"
         );
     }
@@ -789,11 +1448,12 @@ mod tests {
             [
                 ChatCompletionMessage {
                     role: ChatCompletionMessageRole::User,
-                    content: Some("What is the weather in Boston?".to_string()),
+                    content: Some("What is the weather in Boston?".into()),
                     name: None,
                     function_call: None,
                     tool_call_id: None,
                     tool_calls: Some(Vec::new()),
+                    ..Default::default()
                 }
             ]
         ).functions([ChatCompletionFunctionDefinition {
@@ -858,11 +1518,12 @@ mod tests {
             "gpt-3.5-turbo",
             [ChatCompletionMessage {
                 role: ChatCompletionMessageRole::User,
-                content: Some("Write an example JSON for a JWT header using RS256".to_string()),
+                content: Some("Write an example JSON for a JWT header using RS256".into()),
                 name: None,
                 function_call: None,
                 tool_call_id: None,
                 tool_calls: Some(Vec::new()),
+                ..Default::default()
             }],
         )
         .temperature(0.0)
@@ -879,13 +1540,14 @@ mod tests {
             .message
             .content
             .as_ref()
-            .unwrap();
+            .unwrap()
+            .to_text();
         #[derive(Deserialize, Eq, PartialEq, Debug)]
         struct Response {
             alg: String,
             typ: String,
         }
-        let response = serde_json::from_str::<Response>(response_string).unwrap();
+        let response = serde_json::from_str::<Response>(&response_string).unwrap();
         assert_eq!(
             response,
             Response {
@@ -910,11 +1572,354 @@ mod tests {
         assert_ne!(builder_c, builder_d);
     }
 
-    async fn stream_to_completion(
-        mut chat_stream: Receiver<ChatCompletionDelta>,
-    ) -> ChatCompletion {
+    #[test]
+    fn content_serialization() {
+        let text = ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some("Hello!".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&text).unwrap(),
+            serde_json::json!({ "role": "user", "content": "Hello!" })
+        );
+
+        let parts = ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some(
+                vec![
+                    ChatCompletionContentPart::text("What is in this image?"),
+                    ChatCompletionContentPart::image_data(
+                        "image/png",
+                        b"png",
+                        Some(ChatCompletionImageDetail::Low),
+                    ),
+                    ChatCompletionContentPart::input_audio(
+                        b"wav",
+                        ChatCompletionInputAudioFormat::Wav,
+                    ),
+                    ChatCompletionContentPart::file_id("file-abc"),
+                ]
+                .into(),
+            ),
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&parts).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What is in this image?" },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": "data:image/png;base64,cG5n", "detail": "low" }
+                    },
+                    {
+                        "type": "input_audio",
+                        "input_audio": { "data": "d2F2", "format": "wav" }
+                    },
+                    { "type": "file", "file": { "file_id": "file-abc" } }
+                ]
+            })
+        );
+        let round_trip: ChatCompletionMessage = serde_json::from_value(value).unwrap();
+        assert_eq!(round_trip, parts);
+        assert_eq!(
+            round_trip.content.unwrap().to_text(),
+            "What is in this image?"
+        );
+    }
+
+    #[test]
+    fn merge_tool_call_deltas() {
+        let chunks = [
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"get_weather","arguments":""}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":1,"delta":{"role":"assistant","content":"Sun"},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_b","type":"function","function":{"name":"get_time","arguments":"{}"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Paris\"}"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":1,"delta":{"content":"ny"},"finish_reason":"stop"}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+        ];
+        let mut merged: Option<ChatCompletionDelta> = None;
+        for chunk in chunks {
+            let delta: ChatCompletionDelta = serde_json::from_str(chunk).unwrap();
+            match merged.as_mut() {
+                Some(c) => c.merge(delta).unwrap(),
+                None => merged = Some(delta),
+            }
+        }
+        let completion: ChatCompletion = merged.unwrap().into();
+
+        let expected: ChatCompletion = serde_json::from_value(serde_json::json!({
+            "id": "c",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "m",
+            "choices": [
+                {
+                    "index": 0,
+                    "finish_reason": "tool_calls",
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [
+                            {
+                                "id": "call_a",
+                                "type": "function",
+                                "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                            },
+                            {
+                                "id": "call_b",
+                                "type": "function",
+                                "function": { "name": "get_time", "arguments": "{}" }
+                            }
+                        ]
+                    }
+                },
+                {
+                    "index": 1,
+                    "finish_reason": "stop",
+                    "message": { "role": "assistant", "content": "Sunny" }
+                }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(completion, expected);
+    }
+
+    #[test]
+    fn merge_response_fields() {
+        let chunks = [
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","system_fingerprint":"fp_1","service_tier":"default","choices":[{"index":0,"delta":{"role":"assistant","refusal":"I can"},"logprobs":{"content":null,"refusal":[{"token":"I","logprob":-0.5,"bytes":[73],"top_logprobs":[]}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","system_fingerprint":"fp_1","choices":[{"index":0,"delta":{"refusal":"'t.","audio":{"id":"audio_1","data":"AAAA","transcript":"Hel"}},"logprobs":{"content":null,"refusal":[{"token":" can","logprob":-0.25,"bytes":null,"top_logprobs":[]}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"audio":{"data":"BBBB","transcript":"lo","expires_at":100},"annotations":[{"type":"url_citation","url_citation":{"start_index":0,"end_index":5,"url":"https://example.com","title":"Example"}}]},"finish_reason":"stop"}]}"#,
+        ];
+        let mut merged: Option<ChatCompletionDelta> = None;
+        for chunk in chunks {
+            let delta: ChatCompletionDelta = serde_json::from_str(chunk).unwrap();
+            match merged.as_mut() {
+                Some(c) => c.merge(delta).unwrap(),
+                None => merged = Some(delta),
+            }
+        }
+        let completion: ChatCompletion = merged.unwrap().into();
+
+        assert_eq!(completion.system_fingerprint.as_deref(), Some("fp_1"));
+        assert_eq!(completion.service_tier.as_deref(), Some("default"));
+        let choice = &completion.choices[0];
+        assert_eq!(choice.message.refusal.as_deref(), Some("I can't."));
+        assert_eq!(
+            choice.message.audio,
+            Some(ChatCompletionAudio {
+                id: "audio_1".to_string(),
+                data: "AAAABBBB".to_string(),
+                transcript: "Hello".to_string(),
+                expires_at: 100,
+            })
+        );
+        assert_eq!(
+            choice.message.annotations,
+            Some(vec![ChatCompletionAnnotation::UrlCitation {
+                url_citation: ChatCompletionUrlCitation {
+                    start_index: 0,
+                    end_index: 5,
+                    url: "https://example.com".to_string(),
+                    title: "Example".to_string(),
+                },
+            }])
+        );
+        let refusal_logprobs = choice.logprobs.as_ref().unwrap().refusal.as_ref().unwrap();
+        let tokens: Vec<_> = refusal_logprobs.iter().map(|t| t.token.as_str()).collect();
+        assert_eq!(tokens, ["I", " can"]);
+
+        // Annotations are response-only, and audio is sent back by reference.
+        let message = serde_json::to_value(&choice.message).unwrap();
+        assert_eq!(message["audio"], serde_json::json!({ "id": "audio_1" }));
+        assert!(message.get("annotations").is_none());
+    }
+
+    const CHUNK: &str = r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"role":"assistant","content":"Hi"},"finish_reason":"stop"}]}"#;
+
+    #[tokio::test]
+    async fn chat_stream_ends_on_done() {
+        let credentials = serve_responses(vec![sse_response(&[CHUNK, "[DONE]"])]).await;
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap();
+        let delta = stream.next().await.unwrap().unwrap();
+        assert_eq!(delta.choices[0].delta.content.as_deref(), Some("Hi"));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_stream_usage_chunk() {
+        let stream_options = ChatCompletionStreamOptions {
+            include_usage: Some(true),
+            include_obfuscation: Some(false),
+        };
+        let request = ChatCompletion::builder("m", [])
+            .stream(true)
+            .stream_options(stream_options)
+            .build()
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["stream_options"],
+            serde_json::json!({ "include_usage": true, "include_obfuscation": false })
+        );
+
+        let usage_chunk = r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":1,"total_tokens":10,"prompt_tokens_details":{"cached_tokens":0,"audio_tokens":0},"completion_tokens_details":{"reasoning_tokens":0}}}"#;
+        let credentials =
+            serve_responses(vec![sse_response(&[CHUNK, usage_chunk, "[DONE]"])]).await;
+        let stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .stream_options(stream_options)
+            .create_stream()
+            .await
+            .unwrap();
+        let completion = stream_to_completion(stream).await;
+        assert_eq!(completion.choices.len(), 1);
+        let usage = completion.usage.unwrap();
+        assert_eq!(usage.total_tokens, 10);
+        assert_eq!(usage.prompt_tokens_details.unwrap().cached_tokens, Some(0));
+    }
+
+    #[tokio::test]
+    async fn chat_stream_yields_api_errors() {
+        let body = r#"{"error":{"message":"Invalid model","type":"invalid_request_error","param":"model","code":null}}"#;
+        let response = format!(
+            "HTTP/1.1 400 Bad Request\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        let credentials = serve_responses(vec![response]).await;
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap();
+        match stream.next().await.unwrap() {
+            Err(StreamError::Api(error)) => assert_eq!(error.message, "Invalid model"),
+            other => panic!("unexpected stream item: {other:?}"),
+        }
+        assert!(stream.next().await.is_none());
+
+        let credentials = serve_responses(vec![sse_response(&["{not json"])]).await;
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap();
+        assert!(matches!(
+            stream.next().await.unwrap(),
+            Err(StreamError::Deserialize { .. })
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_stream_retries_before_first_chunk() {
+        let unavailable = "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n";
+        let credentials = serve_responses(vec![
+            unavailable.to_string(),
+            sse_response(&[CHUNK, "[DONE]"]),
+        ])
+        .await;
+        let retry_policy = StreamRetryPolicy {
+            initial_delay: std::time::Duration::from_millis(1),
+            ..StreamRetryPolicy::before_first_chunk(1)
+        };
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .stream_retry_policy(retry_policy)
+            .create_stream()
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_stream_interrupted_keeps_partial() {
+        // The connection closes before the announced body length was sent.
+        let body = format!("data: {CHUNK}\n\n");
+        let truncated = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len() + 100
+        );
+        let credentials = serve_responses(vec![truncated]).await;
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .stream_retry_policy(StreamRetryPolicy::before_first_chunk(3))
+            .create_stream()
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        match stream.next().await.unwrap() {
+            Err(StreamError::Interrupted {
+                chunks_received, ..
+            }) => assert_eq!(chunks_received, 1),
+            other => panic!("unexpected stream item: {other:?}"),
+        }
+        assert!(stream.next().await.is_none());
+        let partial: ChatCompletion = stream.into_partial().unwrap().into();
+        assert_eq!(
+            partial.choices[0].message.content,
+            Some(ChatCompletionContent::Text("Hi".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn chat_stream_stops_tracking_on_merge_error() {
+        let other = CHUNK.replace(r#""id":"c""#, r#""id":"d""#);
+        let credentials = serve_responses(vec![sse_response(&[CHUNK, &other, "[DONE]"])]).await;
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.partial().is_some());
+        assert_eq!(stream.next().await.unwrap().unwrap().id, "d");
+        assert!(stream.partial().is_none());
+        assert!(matches!(
+            stream.merge_error(),
+            Some(ChatCompletionDeltaMergeError::DifferentCompletionIds)
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_stream_without_done_is_interrupted() {
+        let credentials = serve_responses(vec![sse_response(&[CHUNK])]).await;
+        let mut stream = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        match stream.next().await.unwrap() {
+            Err(StreamError::Interrupted {
+                error,
+                chunks_received,
+            }) => {
+                assert_eq!(chunks_received, 1);
+                assert!(matches!(
+                    *error,
+                    StreamError::Transport(reqwest_eventsource::Error::StreamEnded)
+                ));
+            }
+            other => panic!("unexpected stream item: {other:?}"),
+        }
+        assert!(stream.next().await.is_none());
+    }
+
+    async fn stream_to_completion(mut chat_stream: ChatCompletionStream) -> ChatCompletion {
         let mut merged: Option<ChatCompletionDelta> = None;
-        while let Some(delta) = chat_stream.recv().await {
+        while let Some(delta) = chat_stream.next().await {
+            let delta = delta.unwrap();
             match merged.as_mut() {
                 Some(c) => {
                     c.merge(delta).unwrap();
@@ -964,8 +1969,7 @@ mod tests {
             [ChatCompletionMessage {
                 role: ChatCompletionMessageRole::User,
                 content: Some(
-                    "Create a DND character, don't use the dont_use_this_property field"
-                        .to_string(),
+                    "Create a DND character, don't use the dont_use_this_property field".into(),
                 ),
                 ..Default::default()
             }],
@@ -975,20 +1979,25 @@ mod tests {
         .create()
         .await
         .unwrap();
-        let character_str = chat_completion.choices[0].message.content.as_ref().unwrap();
-        let _character: Character = serde_json::from_str(character_str).unwrap();
+        let character_str = chat_completion.choices[0]
+            .message
+            .content
+            .as_ref()
+            .unwrap()
+            .to_text();
+        let _character: Character = serde_json::from_str(&character_str).unwrap();
     }
 
     #[tokio::test]
     async fn chat_tool_use_completion() {
         dotenv().ok();
         let credentials = Credentials::from_env();
-        let schema = ChatCompletionTool::new::<Character>(None);
+        let schema = ChatCompletionTool::new::<Character>(None, JsonSchemaStyle::OpenAI);
         let chat_completion = ChatCompletion::builder(
             "gpt-4o-mini",
             [ChatCompletionMessage {
                 role: ChatCompletionMessageRole::User,
-                content: Some("create a random DND character directly with tools".to_string()),
+                content: Some("create a random DND character directly with tools".into()),
                 ..Default::default()
             }],
         )
@@ -1025,16 +2034,17 @@ mod tests {
                         round the number to to 2 decimals \
                         and reply with the result number only, \
                         with no full stop at the end"
-                            .to_string(),
+                            .into(),
                     ),
                     name: None,
                     function_call: None,
                     tool_call_id: None,
                     tool_calls: Some(Vec::new()),
+                    ..Default::default()
                 },
                 ChatCompletionMessage {
                     role: ChatCompletionMessageRole::Assistant,
-                    content: Some("Let me calculate that for you.".to_string()),
+                    content: Some("Let me calculate that for you.".into()),
                     name: None,
                     function_call: None,
                     tool_call_id: None,
@@ -1046,14 +2056,16 @@ mod tests {
                             arguments: "not_required_to_be_valid_here".to_string(),
                         },
                     }]),
+                    ..Default::default()
                 },
                 ChatCompletionMessage {
                     role: ChatCompletionMessageRole::Tool,
-                    content: Some("the result is 25903.061423199997".to_string()),
+                    content: Some("the result is 25903.061423199997".into()),
                     name: None,
                     function_call: None,
                     tool_call_id: Some("the_tool_call".to_string()),
                     tool_calls: Some(Vec::new()),
+                    ..Default::default()
                 },
             ],
         )
@@ -1073,7 +2085,8 @@ mod tests {
                 .message
                 .content
                 .as_ref()
-                .unwrap(),
+                .unwrap()
+                .to_text(),
             "25903.06"
         );
     }
@@ -0,0 +1,222 @@
+//! Decoding of the audio generated with the `Audio` modality.
+
+use super::{ChatCompletionAudio, ChatCompletionAudioFormat};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{ser::SerializeStruct, Serializer};
+use std::path::Path;
+
+/// The sample rate of [`ChatCompletionAudioFormat::Pcm16`] audio.
+pub const PCM16_SAMPLE_RATE: u32 = 24_000;
+
+#[derive(Debug)]
+pub enum ChatCompletionAudioError {
+    Decode(base64::DecodeError),
+    Io(std::io::Error),
+    /// The audio can only be converted to WAV from `Wav` or `Pcm16`.
+    UnsupportedFormat(ChatCompletionAudioFormat),
+}
+
+impl std::fmt::Display for ChatCompletionAudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatCompletionAudioError::Decode(error) => write!(f, "Invalid audio data: {error}"),
+            ChatCompletionAudioError::Io(error) => write!(f, "{error}"),
+            ChatCompletionAudioError::UnsupportedFormat(format) => {
+                write!(f, "Cannot convert {format:?} audio to WAV")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChatCompletionAudioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChatCompletionAudioError::Decode(error) => Some(error),
+            ChatCompletionAudioError::Io(error) => Some(error),
+            ChatCompletionAudioError::UnsupportedFormat(_) => None,
+        }
+    }
+}
+
+impl From<base64::DecodeError> for ChatCompletionAudioError {
+    fn from(value: base64::DecodeError) -> Self {
+        ChatCompletionAudioError::Decode(value)
+    }
+}
+
+impl From<std::io::Error> for ChatCompletionAudioError {
+    fn from(value: std::io::Error) -> Self {
+        ChatCompletionAudioError::Io(value)
+    }
+}
+
+impl ChatCompletionAudio {
+    /// Decodes the audio bytes, in the format of the request.
+    ///
+    /// The data of a streamed response is the concatenation of separately encoded
+    /// chunks, each with its own padding, which are decoded one after the other.
+    pub fn decode(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        let mut bytes = Vec::new();
+        let mut rest = self.data.as_str();
+        while !rest.is_empty() {
+            let end = match rest.find('=') {
+                Some(padding) => {
+                    padding + rest[padding..].bytes().take_while(|b| *b == b'=').count()
+                }
+                None => rest.len(),
+            };
+            bytes.extend(BASE64_STANDARD.decode(&rest[..end])?);
+            rest = &rest[end..];
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes the audio as a WAV file, adding a header to `Pcm16` samples.
+    pub fn decode_wav(
+        &self,
+        format: ChatCompletionAudioFormat,
+    ) -> Result<Vec<u8>, ChatCompletionAudioError> {
+        match format {
+            ChatCompletionAudioFormat::Wav => Ok(self.decode()?),
+            ChatCompletionAudioFormat::Pcm16 => Ok(pcm16_to_wav(&self.decode()?)),
+            format => Err(ChatCompletionAudioError::UnsupportedFormat(format)),
+        }
+    }
+
+    /// Writes the decoded audio bytes to a new local file.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), ChatCompletionAudioError> {
+        std::fs::write(path, self.decode()?)?;
+        Ok(())
+    }
+}
+
+/// Wraps raw [`ChatCompletionAudioFormat::Pcm16`] samples in a WAV header.
+pub fn pcm16_to_wav(pcm: &[u8]) -> Vec<u8> {
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let byte_rate = PCM16_SAMPLE_RATE * block_align as u32;
+    let data_len = pcm.len() as u32;
+
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&PCM16_SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&byte_rate.to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    wav.extend_from_slice(pcm);
+    wav
+}
+
+/// Serializes the audio of an assistant message as the `{"id": ...}` reference the
+/// API expects in later turns.
+pub(super) fn serialize_audio_reference<S: Serializer>(
+    audio: &Option<ChatCompletionAudio>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match audio {
+        Some(audio) => {
+            let mut reference = serializer.serialize_struct("ChatCompletionAudio", 1)?;
+            reference.serialize_field("id", &audio.id)?;
+            reference.end()
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{
+        ChatCompletion, ChatCompletionAudioParameters, ChatCompletionMessage,
+        ChatCompletionMessageRole, ChatCompletionModality, ChatCompletionVoice,
+    };
+    use serde_json::json;
+
+    fn audio(data: &str) -> ChatCompletionAudio {
+        ChatCompletionAudio {
+            id: "audio_1".to_string(),
+            data: data.to_string(),
+            transcript: "Hi".to_string(),
+            expires_at: 1,
+        }
+    }
+
+    #[test]
+    fn decode_concatenated_chunks() {
+        let data = [&[1u8, 2, 3][..], &[4, 5], &[6]]
+            .iter()
+            .map(|chunk| BASE64_STANDARD.encode(chunk))
+            .collect::<String>();
+        assert_eq!(data, "AQIDBAU=Bg==");
+        assert_eq!(audio(&data).decode().unwrap(), [1, 2, 3, 4, 5, 6]);
+        assert!(audio("not base64!").decode().is_err());
+    }
+
+    #[test]
+    fn pcm16_as_wav() {
+        let audio = audio(&BASE64_STANDARD.encode([0u8, 0, 255, 127]));
+        let wav = audio.decode_wav(ChatCompletionAudioFormat::Pcm16).unwrap();
+        assert_eq!(wav.len(), 48);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[24..28], &24_000u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &4u32.to_le_bytes());
+        assert_eq!(&wav[44..], [0, 0, 255, 127]);
+        assert!(matches!(
+            audio.decode_wav(ChatCompletionAudioFormat::Mp3),
+            Err(ChatCompletionAudioError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn audio_request_and_reference() {
+        let request = ChatCompletion::builder(
+            "gpt-4o-audio-preview",
+            [ChatCompletionMessage {
+                role: ChatCompletionMessageRole::Assistant,
+                audio: Some(audio("AAAA")),
+                ..Default::default()
+            }],
+        )
+        .modalities([ChatCompletionModality::Text, ChatCompletionModality::Audio])
+        .audio(ChatCompletionAudioParameters {
+            voice: ChatCompletionVoice::Alloy,
+            format: ChatCompletionAudioFormat::Pcm16,
+        })
+        .build()
+        .unwrap();
+        let request = serde_json::to_value(&request).unwrap();
+        assert_eq!(request["modalities"], json!(["text", "audio"]));
+        assert_eq!(
+            request["audio"],
+            json!({ "voice": "alloy", "format": "pcm16" })
+        );
+        assert_eq!(request["messages"][0]["audio"], json!({ "id": "audio_1" }));
+    }
+
+    #[test]
+    fn message_with_audio_round_trip() {
+        let message = ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Assistant,
+            audio: Some(audio("AAAA")),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        let message: ChatCompletionMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            message.audio,
+            Some(ChatCompletionAudio {
+                id: "audio_1".to_string(),
+                ..Default::default()
+            })
+        );
+    }
+}
@@ -0,0 +1,631 @@
+//! A chat session that keeps its history within the context window of a model.
+//!
+//! ```no_run
+//! use openai::chat::conversation::{Conversation, TruncationStrategy};
+//! use openai::chat::ChatCompletion;
+//!
+//! # async fn run() -> Result<(), openai::chat::conversation::ConversationError> {
+//! let mut conversation = Conversation::new(128_000)
+//!     .strategy(TruncationStrategy::TruncateToolOutputs { max_tokens: 2_000 })
+//!     .strategy(TruncationStrategy::DropOldest);
+//! conversation.push_system("You are a helpful assistant.")?;
+//! conversation.push_user("Hello!")?;
+//! let reply = conversation
+//!     .send(ChatCompletion::builder("gpt-4o-mini", []))
+//!     .await?;
+//! println!("{:?}", reply.content);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{
+    ChatCompletion, ChatCompletionBuilder, ChatCompletionContent, ChatCompletionContentPart,
+    ChatCompletionMessage, ChatCompletionMessageRole,
+};
+use crate::{Credentials, OpenAiError};
+
+/// Estimates how many tokens messages take in the context window.
+pub trait TokenCounter: Send + Sync {
+    /// The number of tokens of a single message, including its formatting overhead.
+    fn count_message(&self, message: &ChatCompletionMessage) -> usize;
+
+    /// The number of tokens of a list of messages, including the tokens priming the reply.
+    fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+        messages
+            .iter()
+            .map(|message| self.count_message(message))
+            .sum::<usize>()
+            + 3
+    }
+}
+
+/// Estimates about four characters per token, which is close for English text.
+///
+/// Non-text content parts count as a fixed 85 tokens, the cost of a low detail image.
+/// Use an exact tokenizer when the budget is tight.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenCounter;
+
+impl TokenCounter for HeuristicTokenCounter {
+    fn count_message(&self, message: &ChatCompletionMessage) -> usize {
+        let text_tokens = |text: &str| text.chars().count().div_ceil(4);
+        let content = match &message.content {
+            Some(ChatCompletionContent::Text(text)) => text_tokens(text),
+            Some(ChatCompletionContent::Parts(parts)) => parts
+                .iter()
+                .map(|part| match part {
+                    ChatCompletionContentPart::Text { text } => text_tokens(text),
+                    _ => 85,
+                })
+                .sum(),
+            None => 0,
+        };
+        let tool_calls: usize = message
+            .tool_calls
+            .iter()
+            .flatten()
+            .map(|call| text_tokens(&call.function.name) + text_tokens(&call.function.arguments))
+            .sum();
+        let name = message.name.as_deref().map_or(0, text_tokens);
+        4 + content + tool_calls + name
+    }
+}
+
+/// A way of making the history fit in the context window. Strategies are applied in
+/// the order they were added, until the history fits.
+#[derive(Debug, Clone)]
+pub enum TruncationStrategy {
+    /// Cuts the content of tool results longer than `max_tokens`.
+    TruncateToolOutputs { max_tokens: usize },
+    /// Drops the oldest turns, one at a time. A turn is a user message with the
+    /// assistant and tool messages answering it, so no reply outlives its question
+    /// and no tool result outlives its call. System and developer messages are kept.
+    /// The latest turn is never dropped.
+    DropOldest,
+    /// Replaces all turns but the `keep_recent` latest ones with a summary written by
+    /// `model`, as a system message. Like with `DropOldest`, the latest turn is always
+    /// kept, even with a `keep_recent` of 0.
+    Summarize {
+        model: String,
+        keep_recent: usize,
+        credentials: Option<Credentials>,
+    },
+}
+
+#[derive(Debug)]
+pub enum ConversationError {
+    /// A request to the API failed.
+    Api(OpenAiError),
+    /// The history does not fit in the context window after applying every strategy.
+    ContextExceeded { tokens: usize, limit: usize },
+    /// A message was added while these tool calls are still waiting for their results.
+    PendingToolCalls(Vec<String>),
+    /// A tool result answers a tool call that is not pending.
+    UnexpectedToolResult(String),
+    /// The completion has no choices.
+    NoChoices,
+}
+
+impl std::fmt::Display for ConversationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversationError::Api(error) => write!(f, "API error: {error}"),
+            ConversationError::ContextExceeded { tokens, limit } => write!(
+                f,
+                "The conversation takes {tokens} tokens, more than the limit of {limit}"
+            ),
+            ConversationError::PendingToolCalls(ids) => {
+                write!(f, "Tool calls are waiting for results: {}", ids.join(", "))
+            }
+            ConversationError::UnexpectedToolResult(id) => {
+                write!(f, "No pending tool call has the id {id}")
+            }
+            ConversationError::NoChoices => f.write_str("The completion has no choices"),
+        }
+    }
+}
+
+impl std::error::Error for ConversationError {}
+
+impl From<OpenAiError> for ConversationError {
+    fn from(value: OpenAiError) -> Self {
+        ConversationError::Api(value)
+    }
+}
+
+const SUMMARY_PROMPT: &str = "Summarize the following conversation in a few sentences. \
+Keep the facts, decisions and open questions needed to continue it.";
+
+/// The message history of a chat session, kept within a token budget.
+pub struct Conversation {
+    messages: Vec<ChatCompletionMessage>,
+    context_window: usize,
+    reserved_output: usize,
+    strategies: Vec<TruncationStrategy>,
+    counter: Box<dyn TokenCounter>,
+}
+
+impl Conversation {
+    /// A conversation for a model with a context window of `context_window` tokens.
+    ///
+    /// Without strategies, sending fails once the history no longer fits.
+    pub fn new(context_window: usize) -> Self {
+        Conversation {
+            messages: Vec::new(),
+            context_window,
+            reserved_output: 0,
+            strategies: Vec::new(),
+            counter: Box::new(HeuristicTokenCounter),
+        }
+    }
+
+    /// Keeps `tokens` of the context window free for the reply.
+    pub fn reserve_output(mut self, tokens: usize) -> Self {
+        self.reserved_output = tokens;
+        self
+    }
+
+    /// Adds a truncation strategy, applied after the ones already added.
+    pub fn strategy(mut self, strategy: TruncationStrategy) -> Self {
+        self.strategies.push(strategy);
+        self
+    }
+
+    /// Replaces the default [`HeuristicTokenCounter`].
+    pub fn token_counter(mut self, counter: impl TokenCounter + 'static) -> Self {
+        self.counter = Box::new(counter);
+        self
+    }
+
+    pub fn messages(&self) -> &[ChatCompletionMessage] {
+        &self.messages
+    }
+
+    pub fn into_messages(self) -> Vec<ChatCompletionMessage> {
+        self.messages
+    }
+
+    /// The number of tokens the history takes.
+    pub fn token_count(&self) -> usize {
+        self.counter.count_messages(&self.messages)
+    }
+
+    /// The number of tokens the history may take.
+    pub fn token_limit(&self) -> usize {
+        self.context_window.saturating_sub(self.reserved_output)
+    }
+
+    /// The ids of the tool calls of the last assistant message that have no result yet.
+    pub fn pending_tool_calls(&self) -> Vec<String> {
+        let Some(position) = self
+            .messages
+            .iter()
+            .rposition(|message| message.role == ChatCompletionMessageRole::Assistant)
+        else {
+            return Vec::new();
+        };
+        let answered: Vec<_> = self.messages[position + 1..]
+            .iter()
+            .filter_map(|message| message.tool_call_id.as_deref())
+            .collect();
+        self.messages[position]
+            .tool_calls
+            .iter()
+            .flatten()
+            .filter(|call| !answered.contains(&call.id.as_str()))
+            .map(|call| call.id.clone())
+            .collect()
+    }
+
+    /// Appends a message, checking that tool results answer pending tool calls and that
+    /// no other message is added before every tool call has its result.
+    pub fn push(&mut self, message: ChatCompletionMessage) -> Result<&mut Self, ConversationError> {
+        let pending = self.pending_tool_calls();
+        if message.role == ChatCompletionMessageRole::Tool {
+            let id = message.tool_call_id.clone().unwrap_or_default();
+            if !pending.contains(&id) {
+                return Err(ConversationError::UnexpectedToolResult(id));
+            }
+        } else if !pending.is_empty() {
+            return Err(ConversationError::PendingToolCalls(pending));
+        }
+        self.messages.push(message);
+        Ok(self)
+    }
+
+    pub fn push_system(
+        &mut self,
+        content: impl Into<ChatCompletionContent>,
+    ) -> Result<&mut Self, ConversationError> {
+        self.push_content(ChatCompletionMessageRole::System, content)
+    }
+
+    pub fn push_developer(
+        &mut self,
+        content: impl Into<ChatCompletionContent>,
+    ) -> Result<&mut Self, ConversationError> {
+        self.push_content(ChatCompletionMessageRole::Developer, content)
+    }
+
+    pub fn push_user(
+        &mut self,
+        content: impl Into<ChatCompletionContent>,
+    ) -> Result<&mut Self, ConversationError> {
+        self.push_content(ChatCompletionMessageRole::User, content)
+    }
+
+    /// Appends the result of the tool call `tool_call_id` of the last assistant message.
+    pub fn push_tool_result(
+        &mut self,
+        tool_call_id: impl Into<String>,
+        content: impl Into<ChatCompletionContent>,
+    ) -> Result<&mut Self, ConversationError> {
+        self.push(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Tool,
+            content: Some(content.into()),
+            tool_call_id: Some(tool_call_id.into()),
+            ..Default::default()
+        })
+    }
+
+    fn push_content(
+        &mut self,
+        role: ChatCompletionMessageRole,
+        content: impl Into<ChatCompletionContent>,
+    ) -> Result<&mut Self, ConversationError> {
+        self.push(ChatCompletionMessage {
+            role,
+            content: Some(content.into()),
+            ..Default::default()
+        })
+    }
+
+    /// Applies the truncation strategies until the history fits in the token limit.
+    pub async fn fit(&mut self) -> Result<(), ConversationError> {
+        let limit = self.token_limit();
+        for strategy in self.strategies.clone() {
+            if self.token_count() <= limit {
+                break;
+            }
+            match strategy {
+                TruncationStrategy::TruncateToolOutputs { max_tokens } => {
+                    self.truncate_tool_outputs(max_tokens)
+                }
+                TruncationStrategy::DropOldest => {
+                    while self.token_count() > limit && self.drop_oldest_turn() {}
+                }
+                TruncationStrategy::Summarize {
+                    model,
+                    keep_recent,
+                    credentials,
+                } => self.summarize(&model, keep_recent, credentials).await?,
+            }
+        }
+        let tokens = self.token_count();
+        if tokens > limit {
+            return Err(ConversationError::ContextExceeded { tokens, limit });
+        }
+        Ok(())
+    }
+
+    /// Fits the history, sends it with the settings of `builder` and appends the reply.
+    pub async fn send(
+        &mut self,
+        builder: ChatCompletionBuilder,
+    ) -> Result<ChatCompletionMessage, ConversationError> {
+        self.fit().await?;
+        let completion = builder.messages(self.messages.clone()).create().await?;
+        let message = completion
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(ConversationError::NoChoices)?
+            .message;
+        self.push(message.clone())?;
+        Ok(message)
+    }
+
+    fn is_pinned(message: &ChatCompletionMessage) -> bool {
+        matches!(
+            message.role,
+            ChatCompletionMessageRole::System | ChatCompletionMessageRole::Developer
+        )
+    }
+
+    /// The ranges of the messages forming each turn, from the oldest. A turn starts
+    /// with a user message and holds the assistant and tool messages that follow it.
+    /// The ranges may contain pinned messages, which belong to no turn.
+    fn turns(&self) -> Vec<std::ops::Range<usize>> {
+        let mut turns: Vec<std::ops::Range<usize>> = Vec::new();
+        for (index, message) in self.messages.iter().enumerate() {
+            if Self::is_pinned(message) {
+                continue;
+            }
+            match turns.last_mut() {
+                Some(turn) if message.role != ChatCompletionMessageRole::User => {
+                    turn.end = index + 1
+                }
+                _ => turns.push(index..index + 1),
+            }
+        }
+        turns
+    }
+
+    fn drop_oldest_turn(&mut self) -> bool {
+        let turns = self.turns();
+        if turns.len() < 2 {
+            return false;
+        }
+        self.remove_unpinned(turns[0].clone());
+        true
+    }
+
+    /// Removes the messages in `range`, except for pinned ones.
+    fn remove_unpinned(&mut self, range: std::ops::Range<usize>) {
+        let mut index = 0;
+        self.messages.retain(|message| {
+            let keep = Self::is_pinned(message) || !range.contains(&index);
+            index += 1;
+            keep
+        });
+    }
+
+    fn truncate_tool_outputs(&mut self, max_tokens: usize) {
+        for message in &mut self.messages {
+            if message.role != ChatCompletionMessageRole::Tool {
+                continue;
+            }
+            let tokens = self.counter.count_message(message);
+            if tokens <= max_tokens {
+                continue;
+            }
+            let text = message
+                .content
+                .as_ref()
+                .map(|c| c.to_text())
+                .unwrap_or_default();
+            let keep = text.chars().count() * max_tokens / tokens;
+            let truncated: String = text.chars().take(keep).collect();
+            message.content = Some(format!("{truncated}\n[truncated]").into());
+        }
+    }
+
+    async fn summarize(
+        &mut self,
+        model: &str,
+        keep_recent: usize,
+        credentials: Option<Credentials>,
+    ) -> Result<(), ConversationError> {
+        let keep_recent = keep_recent.max(1);
+        let turns = self.turns();
+        if turns.len() <= keep_recent {
+            return Ok(());
+        }
+        let old_turns = &turns[..turns.len() - keep_recent];
+        let first = old_turns[0].start;
+        let end = old_turns[old_turns.len() - 1].end;
+        let transcript: String = self.messages[first..end]
+            .iter()
+            .filter(|message| !Self::is_pinned(message))
+            .map(|message| {
+                let content = message.content.as_ref().map(|c| c.to_text());
+                format!("{:?}: {}\n", message.role, content.unwrap_or_default())
+            })
+            .collect();
+
+        let mut builder = ChatCompletion::builder(
+            model,
+            [
+                ChatCompletionMessage {
+                    role: ChatCompletionMessageRole::System,
+                    content: Some(SUMMARY_PROMPT.into()),
+                    ..Default::default()
+                },
+                ChatCompletionMessage {
+                    role: ChatCompletionMessageRole::User,
+                    content: Some(transcript.into()),
+                    ..Default::default()
+                },
+            ],
+        );
+        if let Some(credentials) = credentials {
+            builder = builder.credentials(credentials);
+        }
+        let completion = builder.create().await?;
+        let summary = completion
+            .choices
+            .first()
+            .ok_or(ConversationError::NoChoices)?
+            .message
+            .content
+            .as_ref()
+            .map(|content| content.to_text())
+            .unwrap_or_default();
+
+        self.remove_unpinned(first..end);
+        self.messages.insert(
+            first,
+            ChatCompletionMessage {
+                role: ChatCompletionMessageRole::System,
+                content: Some(format!("Summary of the earlier conversation: {summary}").into()),
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{FunctionType, ToolCall, ToolCallFunction};
+    use crate::tests::{json_response, serve_responses};
+
+    /// Counts one token per message, to make budgets easy to reason about.
+    struct OnePerMessage;
+
+    impl TokenCounter for OnePerMessage {
+        fn count_message(&self, message: &ChatCompletionMessage) -> usize {
+            match message.content.as_ref().map(|c| c.to_text()) {
+                Some(text) if text.ends_with("[truncated]") => 1,
+                Some(text) if text.len() > 100 => 10,
+                _ => 1,
+            }
+        }
+
+        fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+            messages.iter().map(|m| self.count_message(m)).sum()
+        }
+    }
+
+    fn assistant_calling(id: &str) -> ChatCompletionMessage {
+        ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Assistant,
+            tool_calls: Some(vec![ToolCall {
+                id: id.to_string(),
+                r#type: FunctionType::Function,
+                function: ToolCallFunction {
+                    name: "f".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            }]),
+            ..Default::default()
+        }
+    }
+
+    fn assistant(content: &str) -> ChatCompletionMessage {
+        ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Assistant,
+            content: Some(content.into()),
+            ..Default::default()
+        }
+    }
+
+    fn contents(conversation: &Conversation) -> Vec<String> {
+        conversation
+            .messages()
+            .iter()
+            .map(|m| m.content.as_ref().map(|c| c.to_text()).unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn tool_results_must_match_calls() {
+        let mut conversation = Conversation::new(100);
+        conversation.push_user("hi").unwrap();
+        conversation.push(assistant_calling("a")).unwrap();
+        assert!(matches!(
+            conversation.push_user("again"),
+            Err(ConversationError::PendingToolCalls(ids)) if ids == ["a"]
+        ));
+        assert!(matches!(
+            conversation.push_tool_result("b", "result"),
+            Err(ConversationError::UnexpectedToolResult(id)) if id == "b"
+        ));
+        conversation.push_tool_result("a", "result").unwrap();
+        assert!(conversation.pending_tool_calls().is_empty());
+        conversation.push_user("again").unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_pinned_and_tool_pairs() {
+        let mut conversation = Conversation::new(3)
+            .token_counter(OnePerMessage)
+            .strategy(TruncationStrategy::DropOldest);
+        conversation.push_system("system").unwrap();
+        conversation.push_user("first").unwrap();
+        conversation.push(assistant_calling("a")).unwrap();
+        conversation.push_tool_result("a", "result").unwrap();
+        conversation.push_user("second").unwrap();
+        conversation.fit().await.unwrap();
+        // Dropping the call without its result would leave an orphan tool message.
+        assert_eq!(contents(&conversation), ["system", "second"]);
+
+        let mut conversation = Conversation::new(4)
+            .token_counter(OnePerMessage)
+            .strategy(TruncationStrategy::DropOldest);
+        conversation.push_user("first").unwrap();
+        conversation.push_system("system").unwrap();
+        conversation.push(assistant("first reply")).unwrap();
+        conversation.push_user("second").unwrap();
+        conversation.push(assistant("second reply")).unwrap();
+        conversation.fit().await.unwrap();
+        // A reply is dropped together with the message it answers.
+        assert_eq!(
+            contents(&conversation),
+            ["system", "second", "second reply"]
+        );
+
+        let mut conversation = Conversation::new(1)
+            .token_counter(OnePerMessage)
+            .strategy(TruncationStrategy::DropOldest);
+        conversation.push_system("system").unwrap();
+        conversation.push_user("only").unwrap();
+        assert!(matches!(
+            conversation.fit().await,
+            Err(ConversationError::ContextExceeded {
+                tokens: 2,
+                limit: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncate_tool_outputs() {
+        let mut conversation = Conversation::new(3)
+            .token_counter(OnePerMessage)
+            .strategy(TruncationStrategy::TruncateToolOutputs { max_tokens: 5 });
+        conversation.push(assistant_calling("a")).unwrap();
+        conversation.push_tool_result("a", "x".repeat(200)).unwrap();
+        conversation.fit().await.unwrap();
+        assert_eq!(
+            contents(&conversation)[1],
+            format!("{}\n[truncated]", "x".repeat(100))
+        );
+    }
+
+    #[tokio::test]
+    async fn summarize_old_turns() {
+        // The latest turn is kept even with nothing to keep, to have a question to answer.
+        for keep_recent in [1, 0] {
+            summarize_keeping(keep_recent).await;
+        }
+    }
+
+    async fn summarize_keeping(keep_recent: usize) {
+        let body = serde_json::json!({
+            "id": "c",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "m",
+            "choices": [{ "index": 0, "finish_reason": "stop", "message": { "role": "assistant", "content": "They said hello." } }]
+        });
+        let credentials = serve_responses(vec![json_response(&body.to_string())]).await;
+        let mut conversation = Conversation::new(3).token_counter(OnePerMessage).strategy(
+            TruncationStrategy::Summarize {
+                model: "m".to_string(),
+                keep_recent,
+                credentials: Some(credentials),
+            },
+        );
+        conversation.push_developer("developer").unwrap();
+        conversation.push_user("hello").unwrap();
+        conversation
+            .push(ChatCompletionMessage {
+                role: ChatCompletionMessageRole::Assistant,
+                content: Some("hi".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        conversation.push_user("how are you?").unwrap();
+        conversation.fit().await.unwrap();
+        assert_eq!(
+            contents(&conversation),
+            [
+                "developer",
+                "Summary of the earlier conversation: They said hello.",
+                "how are you?"
+            ]
+        );
+    }
+}
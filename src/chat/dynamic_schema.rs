@@ -0,0 +1,473 @@
+//! JSON Schemas defined at runtime, e.g. from configuration, for response formats
+//! and tools.
+//!
+//! A [`DynamicSchema`] runs a schema through the same pipeline as the schemas
+//! generated for Rust types, and validates the outputs following it, returned as
+//! [`Value`]s.
+//!
+//! ```
+//! use openai::chat::dynamic_schema::DynamicSchema;
+//! use openai::chat::structured_output::JsonSchemaStyle;
+//! use openai::chat::ChatCompletionResponseFormat;
+//! use serde_json::json;
+//!
+//! let schema = DynamicSchema::new(
+//!     json!({
+//!         "type": "object",
+//!         "properties": { "city": { "type": "string", "minLength": 1 } },
+//!         "required": ["city"]
+//!     }),
+//!     JsonSchemaStyle::OpenAI,
+//! )
+//! .unwrap();
+//! let format = ChatCompletionResponseFormat::from_dynamic("address", &schema, true).unwrap();
+//!
+//! assert_eq!(schema.parse(r#"{"city":"Paris"}"#).unwrap(), json!({ "city": "Paris" }));
+//! let error = schema.parse(r#"{"city":""}"#).unwrap_err();
+//! assert_eq!(
+//!     error.to_string(),
+//!     "The output does not match the schema: /city: shorter than 1 characters"
+//! );
+//! ```
+
+use schemars::schema::Schema;
+use serde_json::{Map, Value};
+
+use super::schema_lint::SchemaDiagnostic;
+use super::structured_output::{
+    choice_text, normalize_json_schema, sanitize_name, ChatCompletionResponseFormatJsonSchema,
+    JsonSchemaStyle, ParseError, ParsedChatCompletion, ParsedOutput, SchemaSideTable,
+    ToolCallFunctionDefinition,
+};
+use super::validator::SchemaViolation;
+use super::{
+    ChatCompletionBuilder, ChatCompletionChoice, ChatCompletionResponseFormat, ChatCompletionTool,
+};
+use crate::ApiResponseOrError;
+
+/// A JSON Schema defined at runtime, normalized for a [`JsonSchemaStyle`].
+///
+/// Like generated schemas, maps and tuples are rewritten for the styles rewriting
+/// shapes, roots which are not objects are wrapped, and the constraints the style
+/// does not support are stripped and checked locally. The OpenAI style makes every
+/// property required, so optional properties should be nullable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DynamicSchema {
+    schema: Value,
+    description: Option<String>,
+    side_table: SchemaSideTable,
+}
+
+#[derive(Debug)]
+pub enum DynamicSchemaError {
+    /// The value is not a JSON Schema.
+    Invalid(serde_json::Error),
+    /// The schema is not supported by strict mode.
+    Strict(Vec<SchemaDiagnostic>),
+}
+
+impl std::fmt::Display for DynamicSchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DynamicSchemaError::Invalid(error) => write!(f, "Invalid JSON Schema: {error}"),
+            DynamicSchemaError::Strict(diagnostics) => {
+                f.write_str("Invalid strict schema:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{diagnostic}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for DynamicSchemaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DynamicSchemaError::Invalid(error) => Some(error),
+            DynamicSchemaError::Strict(_) => None,
+        }
+    }
+}
+
+impl DynamicSchema {
+    /// Normalizes `schema` for `json_style`. Its definitions may be in `$defs` or
+    /// `definitions`, and are moved to `$defs`.
+    pub fn new(schema: Value, json_style: JsonSchemaStyle) -> Result<Self, DynamicSchemaError> {
+        let mut schema = schema;
+        let mut definitions = Map::new();
+        if let Value::Object(object) = &mut schema {
+            for key in ["definitions", "$defs"] {
+                if let Some(Value::Object(schemas)) = object.remove(key) {
+                    definitions.extend(schemas);
+                }
+            }
+        }
+        let mut definitions = Value::Object(definitions);
+        rename_definition_refs(&mut schema);
+        rename_definition_refs(&mut definitions);
+
+        let schema: Schema = serde_json::from_value(schema).map_err(DynamicSchemaError::Invalid)?;
+        let definitions: schemars::Map<String, Schema> =
+            serde_json::from_value(definitions).map_err(DynamicSchemaError::Invalid)?;
+        let mut schema = schema.into_object();
+        let description = schema.metadata().description.clone();
+        let (schema, side_table) = normalize_json_schema(schema, definitions, json_style);
+        Ok(DynamicSchema {
+            schema,
+            description,
+            side_table,
+        })
+    }
+
+    /// The normalized schema, as sent to the model.
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// The `description` of the root of the schema.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn side_table(&self) -> &SchemaSideTable {
+        &self.side_table
+    }
+
+    /// Validates an output against the schema, including the stripped constraints.
+    pub fn validate(&self, instance: &Value) -> Vec<SchemaViolation> {
+        self.side_table.constraints.check(&self.schema, instance)
+    }
+
+    /// Parses and validates an output, like the arguments of a tool call, and
+    /// converts it back to the shape of the original schema.
+    pub fn parse(&self, content: &str) -> Result<Value, ParseError> {
+        let value = match serde_json::from_str(content) {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(ParseError::Deserialize {
+                    error,
+                    content: content.to_string(),
+                })
+            }
+        };
+        self.adapt(value, content)
+    }
+
+    /// Parses and validates the message of a choice, checking for refusals and
+    /// truncation first.
+    pub fn parse_choice(
+        &self,
+        choice: &ChatCompletionChoice,
+    ) -> Result<ParsedOutput<Value>, ParseError> {
+        match ParsedOutput::<Value>::from_choice(choice)? {
+            ParsedOutput::Refusal(refusal) => Ok(ParsedOutput::Refusal(refusal)),
+            ParsedOutput::Parsed(value) => {
+                let value = self.adapt(value, &choice_text(choice))?;
+                Ok(ParsedOutput::Parsed(value))
+            }
+        }
+    }
+
+    fn adapt(&self, value: Value, content: &str) -> Result<Value, ParseError> {
+        let violations = self.validate(&value);
+        if !violations.is_empty() {
+            return Err(ParseError::Schema {
+                violations,
+                content: content.to_string(),
+            });
+        }
+        self.side_table
+            .deserialize(&self.schema, value)
+            .map_err(|error| ParseError::Deserialize {
+                error,
+                content: content.to_string(),
+            })
+    }
+}
+
+/// Points the `$ref`s to `#/definitions/` at `#/$defs/` instead.
+fn rename_definition_refs(schema: &mut Value) {
+    match schema {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "$ref" => {
+                        if let Some(name) = reference.strip_prefix("#/definitions/") {
+                            *reference = format!("#/$defs/{name}");
+                        }
+                    }
+                    value => rename_definition_refs(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(rename_definition_refs),
+        _ => {}
+    }
+}
+
+fn check_strict(diagnostics: Vec<SchemaDiagnostic>) -> Result<(), DynamicSchemaError> {
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(DynamicSchemaError::Strict(diagnostics))
+    }
+}
+
+impl ChatCompletionResponseFormatJsonSchema {
+    /// A response format with a schema defined at runtime. With `strict`, the
+    /// schema is checked against the limits of strict mode.
+    pub fn from_dynamic(
+        name: &str,
+        schema: &DynamicSchema,
+        strict: bool,
+    ) -> Result<Self, DynamicSchemaError> {
+        let format = ChatCompletionResponseFormatJsonSchema {
+            name: sanitize_name(name),
+            description: schema.description.clone(),
+            schema: Some(schema.schema.clone()),
+            strict: Some(strict),
+        };
+        if strict {
+            check_strict(format.lint())?;
+        }
+        Ok(format)
+    }
+}
+
+impl ChatCompletionResponseFormat {
+    /// See [`ChatCompletionResponseFormatJsonSchema::from_dynamic`].
+    pub fn from_dynamic(
+        name: &str,
+        schema: &DynamicSchema,
+        strict: bool,
+    ) -> Result<Self, DynamicSchemaError> {
+        let json_schema =
+            ChatCompletionResponseFormatJsonSchema::from_dynamic(name, schema, strict)?;
+        Ok(ChatCompletionResponseFormat::JsonSchema { json_schema })
+    }
+}
+
+impl ToolCallFunctionDefinition {
+    /// A function whose parameters are defined at runtime. With `strict`, the
+    /// parameters are checked against the limits of strict mode.
+    pub fn from_dynamic(
+        name: &str,
+        parameters: &DynamicSchema,
+        strict: Option<bool>,
+    ) -> Result<Self, DynamicSchemaError> {
+        let definition = Self::dynamic(name, parameters, strict);
+        if strict == Some(true) {
+            check_strict(definition.lint())?;
+        }
+        Ok(definition)
+    }
+
+    /// Like [`Self::from_dynamic`], without checking the parameters.
+    pub(crate) fn dynamic(name: &str, parameters: &DynamicSchema, strict: Option<bool>) -> Self {
+        ToolCallFunctionDefinition {
+            description: parameters.description.clone(),
+            name: sanitize_name(name),
+            parameters: Some(parameters.schema.clone()),
+            strict,
+        }
+    }
+}
+
+impl ChatCompletionTool {
+    /// See [`ToolCallFunctionDefinition::from_dynamic`].
+    pub fn from_dynamic(
+        name: &str,
+        parameters: &DynamicSchema,
+        strict: Option<bool>,
+    ) -> Result<Self, DynamicSchemaError> {
+        let function = ToolCallFunctionDefinition::from_dynamic(name, parameters, strict)?;
+        Ok(ChatCompletionTool::Function { function })
+    }
+}
+
+impl ChatCompletionBuilder {
+    /// Requests an output following a schema defined at runtime, in strict mode,
+    /// and validates the message of every choice against it.
+    ///
+    /// The name of the response format is sanitized like generated ones. Like any
+    /// strict response format, the schema is only checked against the limits of
+    /// strict mode when built with `lint_strict_schemas(true)`, otherwise a schema
+    /// strict mode does not support is rejected by the API.
+    pub async fn create_dynamic(
+        self,
+        name: &str,
+        schema: &DynamicSchema,
+    ) -> ApiResponseOrError<ParsedChatCompletion<Value>> {
+        let json_schema = ChatCompletionResponseFormatJsonSchema {
+            name: sanitize_name(name),
+            description: schema.description.clone(),
+            schema: Some(schema.schema.clone()),
+            strict: Some(true),
+        };
+        let completion = self
+            .response_format(ChatCompletionResponseFormat::JsonSchema { json_schema })
+            .create()
+            .await?;
+        let choices = completion
+            .choices
+            .iter()
+            .map(|choice| schema.parse_choice(choice))
+            .collect();
+        Ok(ParsedChatCompletion {
+            completion,
+            choices,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::tools::{ToolRegistry, ToolRunner};
+    use crate::chat::{ChatCompletion, ChatCompletionMessageRole};
+    use crate::tests::{json_response, serve_responses};
+    use serde_json::json;
+
+    fn completion_json(message: Value) -> String {
+        json!({
+            "id": "c",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "m",
+            "choices": [{ "index": 0, "finish_reason": "stop", "message": message }]
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn dynamic_schemas() {
+        let schema = DynamicSchema::new(
+            json!({
+                "description": "An order.",
+                "type": "object",
+                "properties": {
+                    "items": { "type": "array", "items": { "$ref": "#/definitions/Item" } },
+                    "notes": { "type": "object", "additionalProperties": { "type": "string" } }
+                },
+                "required": ["items"],
+                "definitions": {
+                    "Item": {
+                        "type": "object",
+                        "properties": { "quantity": { "type": "integer", "minimum": 1 } }
+                    }
+                }
+            }),
+            JsonSchemaStyle::OpenAI,
+        )
+        .unwrap();
+        assert_eq!(schema.description(), Some("An order."));
+        let format =
+            ChatCompletionResponseFormatJsonSchema::from_dynamic("order", &schema, true).unwrap();
+        assert_eq!(format.lint(), []);
+        let normalized = schema.schema();
+        assert_eq!(
+            normalized["properties"]["items"]["items"],
+            json!({ "$ref": "#/$defs/Item" })
+        );
+        assert_eq!(normalized["$defs"]["Item"]["required"], json!(["quantity"]));
+        assert_eq!(normalized["properties"]["notes"]["type"], json!("array"));
+
+        let output = r#"{"items":[{"quantity":2}],"notes":[{"key":"gift","value":"yes"}]}"#;
+        assert_eq!(
+            schema.parse(output).unwrap(),
+            json!({ "items": [{ "quantity": 2 }], "notes": { "gift": "yes" } })
+        );
+        let error = schema
+            .parse(r#"{"items":[{"quantity":0}],"notes":[]}"#)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The output does not match the schema: /items/0/quantity: 0 is less than the minimum of 1"
+        );
+
+        let numbers = DynamicSchema::new(
+            json!({ "type": "array", "items": { "type": "integer" } }),
+            JsonSchemaStyle::Anthropic,
+        )
+        .unwrap();
+        assert!(numbers.side_table().wrapped);
+        assert_eq!(numbers.parse(r#"{"value":[1,2]}"#).unwrap(), json!([1, 2]));
+
+        assert!(matches!(
+            DynamicSchema::new(json!(5), JsonSchemaStyle::OpenAI),
+            Err(DynamicSchemaError::Invalid(_))
+        ));
+        let untyped = DynamicSchema::new(
+            json!({ "type": "object", "properties": { "data": true } }),
+            JsonSchemaStyle::OpenAI,
+        )
+        .unwrap();
+        assert!(ChatCompletionTool::from_dynamic("lookup", &untyped, None).is_ok());
+        let error = ChatCompletionTool::from_dynamic("lookup", &untyped, Some(true)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid strict schema:\n/parameters/properties/data: arbitrary values, like `serde_json::Value`, are not supported, use a typed value"
+        );
+    }
+
+    #[tokio::test]
+    async fn dynamic_requests() {
+        let schema = DynamicSchema::new(
+            json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["city"]
+            }),
+            JsonSchemaStyle::OpenAI,
+        )
+        .unwrap();
+        let tool_calls = json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [
+                { "id": "1", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" } },
+                { "id": "2", "type": "function", "function": { "name": "weather", "arguments": "{\"town\":\"Oslo\"}" } }
+            ]
+        });
+        let answer = json!({ "role": "assistant", "content": "{\"city\":\"Oslo\"}" });
+        let credentials = serve_responses(vec![
+            json_response(&completion_json(tool_calls)),
+            json_response(&completion_json(answer.clone())),
+            json_response(&completion_json(answer)),
+        ])
+        .await;
+
+        let mut registry = ToolRegistry::new();
+        registry.register_dynamic("weather", schema.clone(), |arguments| async move {
+            Ok(format!("Sunny in {}", arguments["city"].as_str().unwrap()))
+        });
+        let output = ToolRunner::new(registry)
+            .run(ChatCompletion::builder("m", []).credentials(credentials.clone()))
+            .await
+            .unwrap();
+        let tool_messages: Vec<String> = output
+            .messages
+            .iter()
+            .filter(|message| message.role == ChatCompletionMessageRole::Tool)
+            .map(|message| message.content.as_ref().unwrap().to_text())
+            .collect();
+        assert_eq!(
+            tool_messages,
+            [
+                "Sunny in Oslo",
+                "Error: invalid arguments: (root): missing required property `city`, (root): unexpected property `town`. Fix the arguments and try again.",
+            ]
+        );
+
+        let parsed = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_dynamic("weather query", &schema)
+            .await
+            .unwrap();
+        assert_eq!(
+            parsed.choices[0].as_ref().unwrap(),
+            &ParsedOutput::Parsed(json!({ "city": "Oslo" }))
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! A higher-level view of a chat completion stream, as semantic events instead of
+//! raw deltas.
+//!
+//! ```no_run
+//! use futures_util::StreamExt;
+//! use openai::chat::events::ChatCompletionEvent;
+//! use openai::chat::{ChatCompletion, ChatCompletionMessage};
+//!
+//! # async fn run() -> Result<(), openai::StreamError> {
+//! let messages: Vec<ChatCompletionMessage> = Vec::new();
+//! let mut events = ChatCompletion::builder("gpt-4o-mini", messages)
+//!     .create_stream()
+//!     .await?
+//!     .events();
+//! while let Some(event) = events.next().await {
+//!     match event? {
+//!         ChatCompletionEvent::ContentDelta { delta, .. } => print!("{delta}"),
+//!         ChatCompletionEvent::ToolCallCompleted { tool_call, .. } => {
+//!             println!("{}({})", tool_call.function.name, tool_call.function.arguments)
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{ChatCompletion, ChatCompletionStream, ToolCall};
+use crate::{StreamError, Usage};
+use futures_util::Stream;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An event of a chat completion stream. Events of a choice carry its `choice_index`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatCompletionEvent {
+    /// A fragment of the message content.
+    ContentDelta { choice_index: u64, delta: String },
+    /// A fragment of the refusal message.
+    RefusalDelta { choice_index: u64, delta: String },
+    /// The model started calling a tool.
+    ToolCallStarted {
+        choice_index: u64,
+        index: i64,
+        id: String,
+        name: String,
+    },
+    /// A fragment of the arguments of the tool call at `index`.
+    ToolCallArgumentsDelta {
+        choice_index: u64,
+        index: i64,
+        delta: String,
+    },
+    /// The arguments of the tool call at `index` are complete.
+    ToolCallCompleted {
+        choice_index: u64,
+        index: i64,
+        tool_call: ToolCall,
+        /// The parsed arguments, or `None` if the model generated invalid JSON.
+        arguments: Option<Value>,
+    },
+    /// The choice is complete, with its finish reason, e.g. `stop` or `tool_calls`.
+    ChoiceFinished { choice_index: u64, reason: String },
+    /// The token usage of the request, if requested with `stream_options`.
+    Usage(Usage),
+    /// The stream ended. Holds every delta merged into a completion.
+    Completed(ChatCompletion),
+}
+
+/// A stream of [`ChatCompletionEvent`]s. Created by [`ChatCompletionStream::events`].
+pub struct ChatCompletionEventStream {
+    stream: ChatCompletionStream,
+    pending: VecDeque<ChatCompletionEvent>,
+    /// The tool calls started and not yet completed, as `(choice_index, index)`.
+    open_tool_calls: Vec<(u64, i64)>,
+    /// Every tool call ever started, as `(choice_index, index)`.
+    started_tool_calls: Vec<(u64, i64)>,
+    ended: bool,
+}
+
+impl ChatCompletionStream {
+    /// Converts the stream of deltas into a stream of semantic events.
+    pub fn events(self) -> ChatCompletionEventStream {
+        ChatCompletionEventStream {
+            stream: self,
+            pending: VecDeque::new(),
+            open_tool_calls: Vec::new(),
+            started_tool_calls: Vec::new(),
+            ended: false,
+        }
+    }
+}
+
+impl ChatCompletionEventStream {
+    /// The underlying stream, which holds the merged completion so far.
+    pub fn stream(&self) -> &ChatCompletionStream {
+        &self.stream
+    }
+
+    /// Completes the open tool calls of a choice, or of every choice if `None`.
+    fn complete_tool_calls(&mut self, choice_index: Option<u64>) {
+        let (completed, open) = std::mem::take(&mut self.open_tool_calls)
+            .into_iter()
+            .partition(|(choice, _)| choice_index.is_none_or(|index| index == *choice));
+        self.open_tool_calls = open;
+        let completed: Vec<(u64, i64)> = completed;
+        for (choice_index, index) in completed {
+            let Some(tool_call) = self
+                .stream
+                .partial()
+                .and_then(|partial| partial.choices.iter().find(|c| c.index == choice_index))
+                .and_then(|choice| choice.delta.tool_calls.as_ref())
+                .and_then(|tool_calls| tool_calls.iter().find(|t| t.index == index))
+            else {
+                continue;
+            };
+            let tool_call = ToolCall::from(tool_call.clone());
+            let arguments = serde_json::from_str(&tool_call.function.arguments).ok();
+            self.pending
+                .push_back(ChatCompletionEvent::ToolCallCompleted {
+                    choice_index,
+                    index,
+                    tool_call,
+                    arguments,
+                });
+        }
+    }
+}
+
+impl Stream for ChatCompletionEventStream {
+    type Item = Result<ChatCompletionEvent, StreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            let delta = match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(error))) => {
+                    // The stream ends with the error, nothing was completed.
+                    this.ended = true;
+                    return Poll::Ready(Some(Err(error)));
+                }
+                Poll::Ready(None) => {
+                    this.ended = true;
+                    this.complete_tool_calls(None);
+                    if let Some(partial) = this.stream.partial() {
+                        let completion = ChatCompletion::from(partial.clone());
+                        this.pending
+                            .push_back(ChatCompletionEvent::Completed(completion));
+                    }
+                    continue;
+                }
+                Poll::Ready(Some(Ok(delta))) => delta,
+            };
+
+            for choice in &delta.choices {
+                let choice_index = choice.index;
+                if let Some(content) = &choice.delta.content {
+                    if !content.is_empty() {
+                        this.pending.push_back(ChatCompletionEvent::ContentDelta {
+                            choice_index,
+                            delta: content.clone(),
+                        });
+                    }
+                }
+                if let Some(refusal) = &choice.delta.refusal {
+                    this.pending.push_back(ChatCompletionEvent::RefusalDelta {
+                        choice_index,
+                        delta: refusal.clone(),
+                    });
+                }
+                for tool_call in choice.delta.tool_calls.iter().flatten() {
+                    let key = (choice_index, tool_call.index);
+                    if !this.started_tool_calls.contains(&key) {
+                        // Tool calls are streamed one after the other, so a new one
+                        // completes the previous ones of the same choice.
+                        this.complete_tool_calls(Some(choice_index));
+                        this.started_tool_calls.push(key);
+                        this.open_tool_calls.push(key);
+                        let function = tool_call.function.as_ref();
+                        this.pending
+                            .push_back(ChatCompletionEvent::ToolCallStarted {
+                                choice_index,
+                                index: tool_call.index,
+                                id: tool_call.id.clone().unwrap_or_default(),
+                                name: function
+                                    .and_then(|function| function.name.clone())
+                                    .unwrap_or_default(),
+                            });
+                    }
+                    let arguments = tool_call
+                        .function
+                        .as_ref()
+                        .and_then(|function| function.arguments.as_ref());
+                    if let Some(arguments) = arguments.filter(|a| !a.is_empty()) {
+                        this.pending
+                            .push_back(ChatCompletionEvent::ToolCallArgumentsDelta {
+                                choice_index,
+                                index: tool_call.index,
+                                delta: arguments.clone(),
+                            });
+                    }
+                }
+                if let Some(reason) = &choice.finish_reason {
+                    this.complete_tool_calls(Some(choice_index));
+                    this.pending.push_back(ChatCompletionEvent::ChoiceFinished {
+                        choice_index,
+                        reason: reason.clone(),
+                    });
+                }
+            }
+            if let Some(usage) = delta.usage {
+                this.pending.push_back(ChatCompletionEvent::Usage(usage));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatCompletion;
+    use crate::tests::{serve_responses, sse_response};
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn stream_events() {
+        let chunks = [
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null},{"index":1,"delta":{"role":"assistant","content":"Hi"},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"a","arguments":"{\"x\":"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"1}"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_b","type":"function","function":{"name":"b","arguments":"{"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"},{"index":1,"delta":{"refusal":"No"},"finish_reason":"stop"}]}"#,
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[],"usage":{"prompt_tokens":1,"completion_tokens":2,"total_tokens":3}}"#,
+            "[DONE]",
+        ];
+        let credentials = serve_responses(vec![sse_response(&chunks)]).await;
+        let events: Vec<_> = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap()
+            .events()
+            .map(|event| event.unwrap())
+            .collect()
+            .await;
+
+        let tool_call = |id: &str, name: &str, arguments: &str| ToolCall {
+            id: id.to_string(),
+            r#type: crate::chat::FunctionType::Function,
+            function: crate::chat::ToolCallFunction {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
+        };
+        use ChatCompletionEvent::*;
+        let (completed, events) = events.split_last().unwrap();
+        assert_eq!(
+            events,
+            [
+                ContentDelta {
+                    choice_index: 1,
+                    delta: "Hi".to_string()
+                },
+                ToolCallStarted {
+                    choice_index: 0,
+                    index: 0,
+                    id: "call_a".to_string(),
+                    name: "a".to_string()
+                },
+                ToolCallArgumentsDelta {
+                    choice_index: 0,
+                    index: 0,
+                    delta: "{\"x\":".to_string()
+                },
+                ToolCallArgumentsDelta {
+                    choice_index: 0,
+                    index: 0,
+                    delta: "1}".to_string()
+                },
+                ToolCallCompleted {
+                    choice_index: 0,
+                    index: 0,
+                    tool_call: tool_call("call_a", "a", "{\"x\":1}"),
+                    arguments: Some(serde_json::json!({ "x": 1 }))
+                },
+                ToolCallStarted {
+                    choice_index: 0,
+                    index: 1,
+                    id: "call_b".to_string(),
+                    name: "b".to_string()
+                },
+                ToolCallArgumentsDelta {
+                    choice_index: 0,
+                    index: 1,
+                    delta: "{".to_string()
+                },
+                ToolCallCompleted {
+                    choice_index: 0,
+                    index: 1,
+                    tool_call: tool_call("call_b", "b", "{"),
+                    arguments: None
+                },
+                ChoiceFinished {
+                    choice_index: 0,
+                    reason: "tool_calls".to_string()
+                },
+                RefusalDelta {
+                    choice_index: 1,
+                    delta: "No".to_string()
+                },
+                ChoiceFinished {
+                    choice_index: 1,
+                    reason: "stop".to_string()
+                },
+                Usage(crate::Usage {
+                    prompt_tokens: 1,
+                    completion_tokens: 2,
+                    total_tokens: 3,
+                    prompt_tokens_details: None,
+                    completion_tokens_details: None,
+                }),
+            ]
+        );
+        let Completed(completion) = completed else {
+            panic!("expected the completion last, got {completed:?}");
+        };
+        assert_eq!(completion.choices.len(), 2);
+        assert_eq!(completion.usage.unwrap().total_tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn interrupted_stream_completes_nothing() {
+        let chunks = [
+            r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"a","arguments":"{\"x\":"}}]},"finish_reason":null}]}"#,
+        ];
+        // The stream closes without `[DONE]`.
+        let credentials = serve_responses(vec![sse_response(&chunks)]).await;
+        let mut events = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap()
+            .events();
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(ChatCompletionEvent::ToolCallStarted { .. }))
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(ChatCompletionEvent::ToolCallArgumentsDelta { .. }))
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(Err(StreamError::Interrupted { .. }))
+        ));
+        assert!(events.next().await.is_none());
+    }
+}
@@ -0,0 +1,345 @@
+//! Analysis of the log probabilities returned when `logprobs` is enabled.
+//!
+//! All helpers work on the `content` tokens of a choice. The content of the message is
+//! exactly the concatenation of the bytes of its tokens, so every token maps to a byte
+//! range of the content.
+
+use super::{ChatCompletionLogprobs, ChatCompletionTokenLogprob, ChatCompletionTopLogprob};
+use std::ops::Range;
+
+impl ChatCompletionTokenLogprob {
+    /// The probability of this token, between 0 and 1.
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+
+    /// The UTF-8 bytes of the token, falling back to the token text.
+    pub fn token_bytes(&self) -> &[u8] {
+        self.bytes.as_deref().unwrap_or(self.token.as_bytes())
+    }
+
+    /// The most likely tokens at this position other than the chosen one, from the
+    /// most to the least likely.
+    pub fn alternatives(&self) -> impl Iterator<Item = &ChatCompletionTopLogprob> {
+        self.top_logprobs
+            .iter()
+            .filter(move |top| top.token != self.token)
+    }
+}
+
+impl ChatCompletionTopLogprob {
+    /// The probability of this token, between 0 and 1.
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+/// The confidence of the model in a value of a JSON output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldConfidence {
+    /// The [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901) to the value, e.g. `/label`.
+    pub pointer: String,
+    /// The byte range of the value in the content. Strings exclude their quotes.
+    pub span: Range<usize>,
+    /// The sum of the log probabilities of the tokens overlapping the value. An empty
+    /// string overlaps no token, so the tokens of its quotes are used instead.
+    pub logprob: f64,
+}
+
+impl FieldConfidence {
+    /// The joint probability of the tokens of the value, between 0 and 1.
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+impl ChatCompletionLogprobs {
+    /// The log probabilities of the content tokens.
+    pub fn tokens(&self) -> &[ChatCompletionTokenLogprob] {
+        self.content.as_deref().unwrap_or_default()
+    }
+
+    /// The content rebuilt from its tokens.
+    pub fn text(&self) -> String {
+        let bytes: Vec<u8> = self
+            .tokens()
+            .iter()
+            .flat_map(|token| token.token_bytes().iter().copied())
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// The log-likelihood of the whole content, i.e. the sum of its token log probabilities.
+    pub fn log_likelihood(&self) -> f64 {
+        self.tokens().iter().map(|token| token.logprob).sum()
+    }
+
+    /// The perplexity of the content, `exp(-mean logprob)`. A value of 1 means the model
+    /// was certain of every token. `None` when there are no tokens.
+    pub fn perplexity(&self) -> Option<f64> {
+        let tokens = self.tokens();
+        if tokens.is_empty() {
+            return None;
+        }
+        Some((-self.log_likelihood() / tokens.len() as f64).exp())
+    }
+
+    /// The byte range of each token in the content, in token order.
+    pub fn token_offsets(&self) -> Vec<Range<usize>> {
+        let mut start = 0;
+        self.tokens()
+            .iter()
+            .map(|token| {
+                let end = start + token.token_bytes().len();
+                let range = start..end;
+                start = end;
+                range
+            })
+            .collect()
+    }
+
+    /// The index of the token that contains the byte at `offset` of the content.
+    pub fn token_at(&self, offset: usize) -> Option<usize> {
+        self.token_offsets()
+            .iter()
+            .position(|range| range.contains(&offset))
+    }
+
+    /// The confidence of every scalar value of a JSON content, such as the output of a
+    /// `json_schema` response format, in document order.
+    ///
+    /// The confidence of a value is the joint probability of the tokens overlapping it.
+    /// Returns an error if the content is not valid JSON, e.g. because it was truncated.
+    pub fn field_confidences(&self) -> Result<Vec<FieldConfidence>, serde_json::Error> {
+        let text = self.text();
+        serde_json::from_str::<serde_json::Value>(&text)?;
+        let mut scanner = JsonScanner {
+            text: text.as_bytes(),
+            position: 0,
+            fields: Vec::new(),
+        };
+        scanner.value(String::new());
+
+        let offsets = self.token_offsets();
+        let tokens = self.tokens();
+        Ok(scanner
+            .fields
+            .into_iter()
+            .map(|(pointer, span)| {
+                let covered = if span.is_empty() {
+                    span.start - 1..span.end + 1
+                } else {
+                    span.clone()
+                };
+                let logprob = offsets
+                    .iter()
+                    .zip(tokens)
+                    .filter(|(range, _)| range.start < covered.end && covered.start < range.end)
+                    .map(|(_, token)| token.logprob)
+                    .sum();
+                FieldConfidence {
+                    pointer,
+                    span,
+                    logprob,
+                }
+            })
+            .collect())
+    }
+
+    /// The confidence of the value at `pointer` of a JSON content.
+    /// See [`ChatCompletionLogprobs::field_confidences`].
+    pub fn field_confidence(&self, pointer: &str) -> Option<FieldConfidence> {
+        self.field_confidences()
+            .ok()?
+            .into_iter()
+            .find(|field| field.pointer == pointer)
+    }
+}
+
+/// Records the span of every scalar of a document already known to be valid JSON.
+struct JsonScanner<'a> {
+    text: &'a [u8],
+    position: usize,
+    fields: Vec<(String, Range<usize>)>,
+}
+
+impl JsonScanner<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn value(&mut self, pointer: String) {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'{') => {
+                self.position += 1;
+                loop {
+                    self.skip_whitespace();
+                    if self.text[self.position] == b'}' {
+                        self.position += 1;
+                        return;
+                    }
+                    let key = self.string();
+                    let key: String =
+                        serde_json::from_slice(&self.text[key.start - 1..key.end + 1])
+                            .unwrap_or_default();
+                    self.skip_whitespace();
+                    // Skip the colon.
+                    self.position += 1;
+                    let key = key.replace('~', "~0").replace('/', "~1");
+                    self.value(format!("{pointer}/{key}"));
+                    self.skip_whitespace();
+                    if self.text[self.position] == b',' {
+                        self.position += 1;
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.position += 1;
+                let mut index = 0;
+                loop {
+                    self.skip_whitespace();
+                    if self.text[self.position] == b']' {
+                        self.position += 1;
+                        return;
+                    }
+                    self.value(format!("{pointer}/{index}"));
+                    index += 1;
+                    self.skip_whitespace();
+                    if self.text[self.position] == b',' {
+                        self.position += 1;
+                    }
+                }
+            }
+            Some(b'"') => {
+                let span = self.string();
+                self.fields.push((pointer, span));
+            }
+            Some(_) => {
+                let start = self.position;
+                while self
+                    .text
+                    .get(self.position)
+                    .is_some_and(|byte| !b",]} \t\r\n".contains(byte))
+                {
+                    self.position += 1;
+                }
+                self.fields.push((pointer, start..self.position));
+            }
+            None => {}
+        }
+    }
+
+    /// Skips a string and returns the range of its contents, without the quotes.
+    fn string(&mut self) -> Range<usize> {
+        self.position += 1;
+        let start = self.position;
+        while self.text[self.position] != b'"' {
+            if self.text[self.position] == b'\\' {
+                self.position += 1;
+            }
+            self.position += 1;
+        }
+        self.position += 1;
+        start..self.position - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logprobs(tokens: &[(&str, f64)]) -> ChatCompletionLogprobs {
+        ChatCompletionLogprobs {
+            content: Some(
+                tokens
+                    .iter()
+                    .map(|(token, logprob)| ChatCompletionTokenLogprob {
+                        token: token.to_string(),
+                        logprob: *logprob,
+                        bytes: Some(token.as_bytes().to_vec()),
+                        top_logprobs: Vec::new(),
+                    })
+                    .collect(),
+            ),
+            refusal: None,
+        }
+    }
+
+    #[test]
+    fn sequence_metrics() {
+        let logprobs = logprobs(&[("Hel", -0.5), ("lo", -1.5)]);
+        assert_eq!(logprobs.text(), "Hello");
+        assert_eq!(logprobs.log_likelihood(), -2.0);
+        assert!((logprobs.perplexity().unwrap() - 1f64.exp()).abs() < 1e-12);
+        assert_eq!(logprobs.token_offsets(), [0..3, 3..5]);
+        assert_eq!(logprobs.token_at(3), Some(1));
+        assert_eq!(logprobs.token_at(5), None);
+        assert_eq!(ChatCompletionLogprobs::default().perplexity(), None);
+    }
+
+    #[test]
+    fn alternatives_skip_chosen_token() {
+        let top = |token: &str, logprob| ChatCompletionTopLogprob {
+            token: token.to_string(),
+            logprob,
+            bytes: None,
+        };
+        let token = ChatCompletionTokenLogprob {
+            token: "yes".to_string(),
+            logprob: -0.1,
+            bytes: None,
+            top_logprobs: vec![top("yes", -0.1), top("no", -2.4)],
+        };
+        let alternatives: Vec<_> = token.alternatives().map(|t| t.token.as_str()).collect();
+        assert_eq!(alternatives, ["no"]);
+    }
+
+    #[test]
+    fn field_confidences() {
+        let logprobs = logprobs(&[
+            ("{\"", 0.0),
+            ("label", 0.0),
+            ("\":\"", 0.0),
+            ("pos", -0.1),
+            ("itive", -0.2),
+            ("\",\"", 0.0),
+            ("a/b", 0.0),
+            ("\":[", 0.0),
+            ("1", -0.3),
+            (",", 0.0),
+            ("true", -0.4),
+            ("]}", 0.0),
+        ]);
+        let fields = logprobs.field_confidences().unwrap();
+        let summary: Vec<_> = fields
+            .iter()
+            .map(|field| {
+                (
+                    field.pointer.as_str(),
+                    (field.logprob * 10.0).round() / 10.0,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [("/label", -0.3), ("/a~1b/0", -0.3), ("/a~1b/1", -0.4)]
+        );
+        assert_eq!(&logprobs.text()[fields[0].span.clone()], "positive");
+        assert!(logprobs.field_confidence("/label").unwrap().probability() < 1.0);
+
+        let empty = self::logprobs(&[("{\"", 0.0), ("a", 0.0), ("\":\"", -0.5), ("\"}", -0.2)]);
+        let field = empty.field_confidence("/a").unwrap();
+        assert!(field.span.is_empty());
+        assert_eq!((field.logprob * 10.0).round() / 10.0, -0.7);
+
+        let truncated = self::logprobs(&[("{\"", 0.0), ("label", 0.0)]);
+        assert!(truncated.field_confidences().is_err());
+    }
+}
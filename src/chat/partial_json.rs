@@ -0,0 +1,533 @@
+//! Progressive parsing of streamed JSON content, such as a streamed `json_schema`
+//! response format, so structured answers can be shown while they are generated.
+//!
+//! Snapshots are parsed as the [`Partial`] version of the answer type, where every
+//! field may still be missing. `#[derive(PartialType)]` generates it for a struct
+//! `Answer` as `PartialAnswer`, with every field optional and nested structs partial
+//! too. A field whose value cannot be parsed yet, like an unfinished enum variant,
+//! is `None`. Enums deriving `PartialType` are their own partial type.
+//!
+//! ```no_run
+//! use futures_util::StreamExt;
+//! use openai::chat::partial_json::PartialType;
+//! use openai::chat::{ChatCompletion, ChatCompletionMessage};
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize, PartialType)]
+//! struct Answer {
+//!     title: String,
+//!     steps: Vec<String>,
+//! }
+//!
+//! # async fn run() -> Result<(), openai::StreamError> {
+//! let messages: Vec<ChatCompletionMessage> = Vec::new();
+//! let mut stream = ChatCompletion::builder("gpt-4o-mini", messages)
+//!     .create_stream()
+//!     .await?
+//!     .partial_json::<Answer>();
+//! while let Some(snapshot) = stream.next().await {
+//!     let answer: PartialAnswer = snapshot?.parse().unwrap_or_default();
+//!     println!("{:?} {:?}", answer.title, answer.steps);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::ChatCompletionStream;
+use crate::StreamError;
+use futures_util::Stream;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Parses the longest meaningful value from a prefix of a JSON document.
+///
+/// Open strings, arrays and objects are closed, so `{"a": "Hel` gives `{"a": "Hel"}`.
+/// Keys without a value yet, unfinished literals, and numbers that no delimiter
+/// follows yet, like the `1` of `{"n": 1`, are left out. Returns `None` if no value has started yet, or if
+/// the text is not the prefix of a JSON document.
+pub fn parse_partial_json(text: &str) -> Option<Value> {
+    PartialJsonParser { text, position: 0 }
+        .value()
+        .map(|(value, _)| value)
+}
+
+struct PartialJsonParser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl PartialJsonParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    /// Parses a value, returning whether it is complete.
+    fn value(&mut self) -> Option<(Value, bool)> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'{' => Some(self.object()),
+            b'[' => Some(self.array()),
+            b'"' => self
+                .string()
+                .map(|(string, complete)| (Value::String(string), complete)),
+            b't' => self.literal("true", Value::Bool(true)),
+            b'f' => self.literal("false", Value::Bool(false)),
+            b'n' => self.literal("null", Value::Null),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => None,
+        }
+    }
+
+    fn object(&mut self) -> (Value, bool) {
+        self.position += 1;
+        let mut object = Map::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b'}') => {
+                    self.position += 1;
+                    return (Value::Object(object), true);
+                }
+                Some(b',') => self.position += 1,
+                Some(b'"') => {
+                    let Some((key, true)) = self.string() else {
+                        return (Value::Object(object), false);
+                    };
+                    self.skip_whitespace();
+                    if self.peek() != Some(b':') {
+                        return (Value::Object(object), false);
+                    }
+                    self.position += 1;
+                    match self.value() {
+                        Some((value, complete)) => {
+                            object.insert(key, value);
+                            if !complete {
+                                return (Value::Object(object), false);
+                            }
+                        }
+                        None => return (Value::Object(object), false),
+                    }
+                }
+                _ => return (Value::Object(object), false),
+            }
+        }
+    }
+
+    fn array(&mut self) -> (Value, bool) {
+        self.position += 1;
+        let mut array = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b']') => {
+                    self.position += 1;
+                    return (Value::Array(array), true);
+                }
+                Some(b',') => self.position += 1,
+                Some(_) => match self.value() {
+                    Some((value, complete)) => {
+                        array.push(value);
+                        if !complete {
+                            return (Value::Array(array), false);
+                        }
+                    }
+                    None => return (Value::Array(array), false),
+                },
+                None => return (Value::Array(array), false),
+            }
+        }
+    }
+
+    /// Parses a string, cutting an unterminated one before any incomplete escape.
+    fn string(&mut self) -> Option<(String, bool)> {
+        self.position += 1;
+        let start = self.position;
+        let mut last_escape = None;
+        let bytes = self.text.as_bytes();
+        while let Some(&byte) = bytes.get(self.position) {
+            match byte {
+                b'"' => {
+                    let raw = &self.text[start - 1..=self.position];
+                    self.position += 1;
+                    return serde_json::from_str(raw).ok().map(|s| (s, true));
+                }
+                b'\\' => {
+                    last_escape = Some(self.position);
+                    self.position += 2;
+                }
+                _ => self.position += 1,
+            }
+        }
+        self.position = self.text.len();
+        let decode = |end: usize| serde_json::from_str(&format!("\"{}\"", &self.text[start..end]));
+        match decode(self.text.len()) {
+            Ok(string) => Some((string, false)),
+            // The text ends in the middle of an escape sequence.
+            Err(_) => decode(last_escape?).ok().map(|s| (s, false)),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Option<(Value, bool)> {
+        let rest = &self.text[self.position..];
+        if rest.starts_with(literal) {
+            self.position += literal.len();
+            Some((value, true))
+        } else {
+            // An unfinished literal has no value yet.
+            self.position = self.text.len();
+            None
+        }
+    }
+
+    fn number(&mut self) -> Option<(Value, bool)> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|byte| matches!(byte, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E'))
+        {
+            self.position += 1;
+        }
+        if self.position == self.text.len() {
+            // More digits may follow, so the number is not known yet.
+            return None;
+        }
+        let number = serde_json::from_str(&self.text[start..self.position]).ok()?;
+        Some((Value::Number(number), true))
+    }
+}
+
+pub use openai_macros::PartialType;
+
+/// A type with a version that can be parsed from an incomplete JSON document.
+///
+/// Derive it with `#[derive(PartialType)]`, see the [module documentation](self).
+pub trait PartialType {
+    /// The type of the snapshots of a streamed `Self`.
+    type Partial: DeserializeOwned + Clone + Debug + PartialEq;
+}
+
+/// The partial version of `T`, such as `PartialAnswer` for a struct `Answer`.
+pub type Partial<T> = <T as PartialType>::Partial;
+
+macro_rules! complete_partial_types {
+    ($($type:ty),*) => {
+        $(impl PartialType for $type {
+            type Partial = Self;
+        })*
+    };
+}
+
+// Scalars are only parsed once they are complete, except for strings which grow.
+complete_partial_types!(
+    bool, char, String, Value, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32,
+    f64
+);
+
+impl<T: PartialType> PartialType for Option<T> {
+    type Partial = Option<T::Partial>;
+}
+
+impl<T: PartialType> PartialType for Box<T> {
+    type Partial = Box<T::Partial>;
+}
+
+impl<T: PartialType> PartialType for Vec<T> {
+    type Partial = Vec<T::Partial>;
+}
+
+impl<T: PartialType> PartialType for HashMap<String, T> {
+    type Partial = HashMap<String, T::Partial>;
+}
+
+impl<T: PartialType> PartialType for BTreeMap<String, T> {
+    type Partial = BTreeMap<String, T::Partial>;
+}
+
+/// Deserializes a field of a partial type, or `None` if its value cannot be parsed
+/// yet. Used by the code generated by `#[derive(PartialType)]`.
+#[doc(hidden)]
+pub fn deserialize_lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(T::deserialize(value).ok())
+}
+
+/// A snapshot of a JSON content being streamed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialSnapshot<T> {
+    value: Value,
+    complete: bool,
+    target: PhantomData<fn() -> T>,
+}
+
+impl<T> PartialSnapshot<T> {
+    /// The value parsed so far.
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn into_value(self) -> Value {
+        self.value
+    }
+
+    /// Whether the content has finished streaming.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The value at a [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901) such as
+    /// `/steps/0`, if it is already present and has the expected type.
+    pub fn get<F: DeserializeOwned>(&self, pointer: &str) -> Option<F> {
+        F::deserialize(self.value.pointer(pointer)?).ok()
+    }
+}
+
+impl<T: PartialType> PartialSnapshot<T> {
+    /// Deserializes the snapshot as the [`Partial`] version of `T`.
+    ///
+    /// Fields that are missing or cannot be parsed yet are `None`, so this only fails
+    /// if the value does not have the shape of `T` at all, e.g. is not an object.
+    pub fn parse(&self) -> Result<Partial<T>, serde_json::Error> {
+        Partial::<T>::deserialize(&self.value)
+    }
+}
+
+impl<T: DeserializeOwned> PartialSnapshot<T> {
+    /// Deserializes a complete snapshot as `T`.
+    pub fn parse_complete(&self) -> Result<T, serde_json::Error> {
+        T::deserialize(&self.value)
+    }
+}
+
+/// Yields a [`PartialSnapshot`] of the content of one choice every time it changes.
+/// Created by [`ChatCompletionStream::partial_json`].
+pub struct PartialJsonStream<T> {
+    stream: ChatCompletionStream,
+    choice_index: u64,
+    content: String,
+    last: Option<Value>,
+    finished: bool,
+    target: PhantomData<fn() -> T>,
+}
+
+impl<T> PartialJsonStream<T> {
+    /// Follows the choice at `index` instead of the first one.
+    pub fn choice(mut self, index: u64) -> Self {
+        self.choice_index = index;
+        self
+    }
+
+    /// The underlying stream, which holds the merged completion so far.
+    pub fn stream(&self) -> &ChatCompletionStream {
+        &self.stream
+    }
+}
+
+impl<T> Stream for PartialJsonStream<T> {
+    type Item = Result<PartialSnapshot<T>, StreamError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.finished {
+                return Poll::Ready(None);
+            }
+            let delta = match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error))),
+                Poll::Ready(Some(Ok(delta))) => delta,
+            };
+            let Some(choice) = delta
+                .choices
+                .iter()
+                .find(|choice| choice.index == this.choice_index)
+            else {
+                continue;
+            };
+            if let Some(content) = &choice.delta.content {
+                this.content.push_str(content);
+            }
+            this.finished = choice.finish_reason.is_some();
+            // A finished content can end in a number, which only a full parse accepts.
+            let value = if this.finished {
+                serde_json::from_str(&this.content).ok()
+            } else {
+                None
+            };
+            let Some(value) = value.or_else(|| parse_partial_json(&this.content)) else {
+                continue;
+            };
+            if !this.finished && this.last.as_ref() == Some(&value) {
+                continue;
+            }
+            this.last = Some(value.clone());
+            return Poll::Ready(Some(Ok(PartialSnapshot {
+                value,
+                complete: this.finished,
+                target: PhantomData,
+            })));
+        }
+    }
+}
+
+impl ChatCompletionStream {
+    /// Parses the streamed content of the first choice as JSON, yielding a snapshot
+    /// every time the parsed value changes. The last snapshot is complete.
+    pub fn partial_json<T>(self) -> PartialJsonStream<T> {
+        PartialJsonStream {
+            stream: self,
+            choice_index: 0,
+            content: String::new(),
+            last: None,
+            finished: false,
+            target: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatCompletion;
+    use crate::tests::{serve_responses, sse_response};
+    use futures_util::StreamExt;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[test]
+    fn parse_prefixes() {
+        let cases = [
+            ("", None),
+            ("  {", Some(json!({}))),
+            (r#"{"ti"#, Some(json!({}))),
+            (r#"{"title""#, Some(json!({}))),
+            (r#"{"title": "Hel"#, Some(json!({ "title": "Hel" }))),
+            (r#"{"title": "a\"#, Some(json!({ "title": "a" }))),
+            (r#"{"title": "a\u00e"#, Some(json!({ "title": "a" }))),
+            (
+                r#"{"title": "a\n", "n": 1"#,
+                Some(json!({ "title": "a\n" })),
+            ),
+            (r#"{"n": 12,"#, Some(json!({ "n": 12 }))),
+            ("[1, 2", Some(json!([1]))),
+            ("12", None),
+            (r#"{"n": 1., "#, Some(json!({}))),
+            (r#"{"n": -"#, Some(json!({}))),
+            (r#"{"ok": tr"#, Some(json!({}))),
+            (
+                r#"{"steps": ["a", true, null, "b"#,
+                Some(json!({ "steps": ["a", true, null, "b"] })),
+            ),
+            (
+                r#"{"a": {"b": [1, {"c": "d"}]}} trailing"#,
+                Some(json!({ "a": { "b": [1, { "c": "d" }] } })),
+            ),
+            ("not json", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_partial_json(text), expected, "{text}");
+        }
+    }
+
+    #[derive(Deserialize, PartialType, Debug, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Answer {
+        title: String,
+        steps: Vec<Step>,
+        cook_time: Option<u32>,
+    }
+
+    #[derive(Deserialize, PartialType, Debug, Clone, PartialEq)]
+    struct Step {
+        #[serde(rename = "do")]
+        action: String,
+        heat: Heat,
+    }
+
+    #[derive(Deserialize, PartialType, Debug, Clone, PartialEq)]
+    enum Heat {
+        Low,
+        High,
+    }
+
+    #[tokio::test]
+    async fn partial_json_stream() {
+        let chunk = |content: &str, finish_reason: Value| {
+            json!({
+                "id": "c",
+                "object": "chat.completion.chunk",
+                "created": 1,
+                "model": "m",
+                "choices": [{ "index": 0, "delta": { "content": content }, "finish_reason": finish_reason }]
+            })
+            .to_string()
+        };
+        let chunks = [
+            chunk(r#"{"title": "Pa"#, Value::Null),
+            chunk(r#"sta", "st"#, Value::Null),
+            chunk(r#"eps": [{"do": "boil", "heat": "Hi"#, Value::Null),
+            chunk(r#"gh"}], "cookTime": 1"#, Value::Null),
+            chunk(r#"2}"#, json!("stop")),
+        ];
+        let events: Vec<_> = chunks.iter().map(String::as_str).collect();
+        let credentials = serve_responses(vec![sse_response(&events)]).await;
+        let snapshots: Vec<_> = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_stream()
+            .await
+            .unwrap()
+            .partial_json::<Answer>()
+            .map(|snapshot| snapshot.unwrap())
+            .collect()
+            .await;
+
+        let parsed: Vec<_> = snapshots.iter().map(|s| s.parse().unwrap()).collect();
+        let boil = |heat| PartialStep {
+            action: Some("boil".to_string()),
+            heat,
+        };
+        let pasta = |steps, cook_time| PartialAnswer {
+            title: Some("Pasta".to_string()),
+            steps,
+            cook_time,
+        };
+        assert_eq!(
+            parsed,
+            [
+                PartialAnswer {
+                    title: Some("Pa".to_string()),
+                    ..Default::default()
+                },
+                pasta(None, None),
+                // The unfinished variant cannot be parsed yet.
+                pasta(Some(vec![boil(None)]), None),
+                // The cooking time may still get more digits.
+                pasta(Some(vec![boil(Some(Heat::High))]), None),
+                pasta(Some(vec![boil(Some(Heat::High))]), Some(12)),
+            ]
+        );
+        assert!(!snapshots[3].is_complete());
+        assert!(snapshots[4].is_complete());
+        assert_eq!(
+            snapshots[4].get::<String>("/steps/0/do").as_deref(),
+            Some("boil")
+        );
+        assert_eq!(snapshots[4].parse_complete().unwrap().cook_time, Some(12));
+    }
+}
@@ -0,0 +1,664 @@
+//! Checks schemas against the limits of OpenAI strict structured outputs, before
+//! sending a request.
+//!
+//! See the [supported schemas](https://platform.openai.com/docs/guides/structured-outputs#supported-schemas)
+//! for the documented limits. These limits only apply to OpenAI, so requests are
+//! only checked when built with `lint_strict_schemas(true)`: their schemas with
+//! `strict: true` then fail to build with every diagnostic.
+//!
+//! ```
+//! use openai::chat::structured_output::ChatCompletionResponseFormatJsonSchema;
+//! use serde_json::json;
+//!
+//! let format = ChatCompletionResponseFormatJsonSchema {
+//!     name: "answer".to_string(),
+//!     description: None,
+//!     schema: Some(json!({
+//!         "type": "object",
+//!         "properties": { "value": { "type": "string", "minLength": 1 } },
+//!         "required": ["value"],
+//!         "additionalProperties": false
+//!     })),
+//!     strict: Some(true),
+//! };
+//! let diagnostics = format.lint();
+//! assert_eq!(
+//!     diagnostics[0].to_string(),
+//!     "/schema/properties/value: unsupported keyword `minLength`"
+//! );
+//! ```
+
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+
+use super::structured_output::{
+    ChatCompletionResponseFormatJsonSchema, JsonSchemaStyle, SchemaTransform,
+    ToolCallFunctionDefinition,
+};
+
+/// The maximum number of object properties in a schema.
+pub const MAX_PROPERTIES: usize = 5000;
+/// The maximum number of nested objects.
+pub const MAX_NESTING_DEPTH: usize = 10;
+/// The maximum number of enum values in a schema.
+pub const MAX_ENUM_VALUES: usize = 1000;
+/// The number of values above which the values of an enum must fit in [`MAX_LARGE_ENUM_LENGTH`].
+pub const LARGE_ENUM_VALUES: usize = 250;
+/// The maximum total length of the string values of an enum with over [`LARGE_ENUM_VALUES`] values.
+pub const MAX_LARGE_ENUM_LENGTH: usize = 15_000;
+/// The maximum total length of the property names, definition names, enum and const values.
+pub const MAX_TOTAL_STRING_LENGTH: usize = 120_000;
+/// The maximum length of a response format or function name.
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// The keywords strict mode rejects.
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "allOf",
+    "oneOf",
+    "not",
+    "if",
+    "then",
+    "else",
+    "dependentRequired",
+    "dependentSchemas",
+    "minLength",
+    "maxLength",
+    "patternProperties",
+    "unevaluatedProperties",
+    "propertyNames",
+    "minProperties",
+    "maxProperties",
+    "unevaluatedItems",
+    "contains",
+    "minContains",
+    "maxContains",
+    "uniqueItems",
+];
+
+/// A problem of a schema, at the JSON pointer of the offending value.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SchemaDiagnostic {
+    /// The JSON pointer of the offending value, from the linted format or function.
+    pub pointer: String,
+    pub problem: SchemaProblem,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SchemaProblem {
+    /// The name is empty, longer than 64 characters, or not made of a-z, A-Z, 0-9,
+    /// underscores and dashes.
+    InvalidName(String),
+    /// The schema is missing.
+    MissingSchema,
+    /// The root of the schema is not a plain object, e.g. an `anyOf`.
+    RootNotObject,
+    UnsupportedKeyword(String),
+    /// An object does not set `additionalProperties` to `false`.
+    AdditionalPropertiesNotFalse,
+    /// An object accepts arbitrary keys, like a `HashMap`.
+    OpenObject,
+    /// Any value is accepted, like a `serde_json::Value`.
+    ArbitraryValue,
+    /// A property is missing from `required`. Optional fields must be nullable instead.
+    NotRequired(String),
+    /// A `$ref` that is not local to the schema.
+    ExternalRef(String),
+    TooDeep {
+        depth: usize,
+    },
+    TooManyProperties {
+        count: usize,
+    },
+    TooManyEnumValues {
+        count: usize,
+    },
+    /// An enum with over [`LARGE_ENUM_VALUES`] values whose strings are too long.
+    LargeEnumTooLong {
+        length: usize,
+    },
+    /// The property names, definition names, enum and const values are too long.
+    StringsTooLong {
+        length: usize,
+    },
+    /// The object takes properties it does not list, because of a
+    /// `#[serde(flatten)]` map field.
+    FlattenedMap,
+}
+
+impl std::fmt::Display for SchemaProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaProblem::InvalidName(name) => write!(
+                f,
+                "invalid name `{name}`, must be 1 to {MAX_NAME_LENGTH} characters of a-z, A-Z, 0-9, `_` or `-`"
+            ),
+            SchemaProblem::MissingSchema => write!(f, "missing schema"),
+            SchemaProblem::RootNotObject => write!(f, "the root must be an object"),
+            SchemaProblem::UnsupportedKeyword(keyword) => {
+                write!(f, "unsupported keyword `{keyword}`")
+            }
+            SchemaProblem::AdditionalPropertiesNotFalse => {
+                write!(f, "`additionalProperties` must be `false`")
+            }
+            SchemaProblem::OpenObject => write!(
+                f,
+                "objects with arbitrary keys are not supported, use an array of entries"
+            ),
+            SchemaProblem::ArbitraryValue => write!(
+                f,
+                "arbitrary values, like `serde_json::Value`, are not supported, use a typed value"
+            ),
+            SchemaProblem::NotRequired(property) => write!(
+                f,
+                "property `{property}` must be required, make it nullable to be optional"
+            ),
+            SchemaProblem::ExternalRef(reference) => {
+                write!(f, "`$ref` `{reference}` is not local to the schema")
+            }
+            SchemaProblem::TooDeep { depth } => write!(
+                f,
+                "{depth} levels of nesting, at most {MAX_NESTING_DEPTH} are supported"
+            ),
+            SchemaProblem::TooManyProperties { count } => write!(
+                f,
+                "{count} properties, at most {MAX_PROPERTIES} are supported"
+            ),
+            SchemaProblem::TooManyEnumValues { count } => write!(
+                f,
+                "{count} enum values, at most {MAX_ENUM_VALUES} are supported"
+            ),
+            SchemaProblem::LargeEnumTooLong { length } => write!(
+                f,
+                "the enum values are {length} characters long, at most {MAX_LARGE_ENUM_LENGTH} are supported for over {LARGE_ENUM_VALUES} values"
+            ),
+            SchemaProblem::StringsTooLong { length } => write!(
+                f,
+                "the names and values are {length} characters long, at most {MAX_TOTAL_STRING_LENGTH} are supported"
+            ),
+            SchemaProblem::FlattenedMap => write!(
+                f,
+                "`#[serde(flatten)]` map fields are not supported, use a map field without `flatten`"
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for SchemaDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.pointer, self.problem)
+    }
+}
+
+impl ChatCompletionResponseFormatJsonSchema {
+    /// Checks the name and schema against the limits of strict mode.
+    pub fn lint(&self) -> Vec<SchemaDiagnostic> {
+        lint_named_schema(&self.name, self.schema.as_ref(), "/schema")
+    }
+}
+
+impl ToolCallFunctionDefinition {
+    /// Checks the name and parameters against the limits of strict mode.
+    pub fn lint(&self) -> Vec<SchemaDiagnostic> {
+        lint_named_schema(&self.name, self.parameters.as_ref(), "/parameters")
+    }
+}
+
+fn lint_named_schema(name: &str, schema: Option<&Value>, pointer: &str) -> Vec<SchemaDiagnostic> {
+    let mut diagnostics = Vec::new();
+    if !is_valid_name(name) {
+        diagnostics.push(SchemaDiagnostic {
+            pointer: "/name".to_string(),
+            problem: SchemaProblem::InvalidName(name.to_string()),
+        });
+    }
+    match schema {
+        Some(schema) => diagnostics.extend(lint_strict_schema(schema, pointer)),
+        None => diagnostics.push(SchemaDiagnostic {
+            pointer: pointer.to_string(),
+            problem: SchemaProblem::MissingSchema,
+        }),
+    }
+    diagnostics
+}
+
+/// Checks the schema generated for `T` in the OpenAI style against the limits of
+/// strict mode, e.g. in a test or at startup, to reject the types strict mode
+/// cannot represent before sending a request.
+///
+/// Maps, tuples and flattened enums are rewritten into supported shapes, and roots
+/// which are not objects are wrapped, while arbitrary values are rejected.
+/// A `#[serde(flatten)]` map field is reported when its values are arbitrary,
+/// like `serde_json::Value`. Flattened maps of other values are left out of the
+/// schema by `schemars`, so they cannot be detected.
+pub fn lint_type<T: JsonSchema>() -> Vec<SchemaDiagnostic> {
+    let mut diagnostics =
+        ChatCompletionResponseFormatJsonSchema::new::<T>(true, JsonSchemaStyle::OpenAI).lint();
+    // Before the style closes the objects, so as generated by `schemars`.
+    let schema = JsonSchemaStyle::OpenAI
+        .settings()
+        .into_generator()
+        .into_root_schema_for::<T>();
+    let schema = serde_json::to_value(schema).expect("unreachable");
+    find_flattened_maps(&schema, "/schema".to_string(), &mut diagnostics);
+    diagnostics
+}
+
+/// Reports the objects with properties which also take unlisted properties, which
+/// `schemars` generates for flattened maps.
+fn find_flattened_maps(schema: &Value, pointer: String, diagnostics: &mut Vec<SchemaDiagnostic>) {
+    let Value::Object(schema) = schema else {
+        return;
+    };
+    let has_properties = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .is_some_and(|properties| !properties.is_empty());
+    let additional_properties = schema.get("additionalProperties");
+    if has_properties && additional_properties.is_some_and(|value| *value != Value::Bool(false)) {
+        diagnostics.push(SchemaDiagnostic {
+            pointer: pointer.clone(),
+            problem: SchemaProblem::FlattenedMap,
+        });
+    }
+
+    for key in ["properties", "definitions", "$defs"] {
+        if let Some(Value::Object(subschemas)) = schema.get(key) {
+            for (name, subschema) in subschemas {
+                let pointer = format!("{pointer}/{key}/{}", escape(name));
+                find_flattened_maps(subschema, pointer, diagnostics);
+            }
+        }
+    }
+    for key in ["items", "anyOf", "oneOf", "allOf"] {
+        match schema.get(key) {
+            Some(Value::Array(subschemas)) => {
+                for (index, subschema) in subschemas.iter().enumerate() {
+                    find_flattened_maps(subschema, format!("{pointer}/{key}/{index}"), diagnostics);
+                }
+            }
+            Some(subschema) => {
+                find_flattened_maps(subschema, format!("{pointer}/{key}"), diagnostics)
+            }
+            None => {}
+        }
+    }
+    if let Some(subschema) = additional_properties {
+        find_flattened_maps(
+            subschema,
+            format!("{pointer}/additionalProperties"),
+            diagnostics,
+        );
+    }
+}
+
+/// Whether a response format or function name is valid.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Checks a schema against the limits of strict mode. The pointers of the
+/// diagnostics start with `pointer`, the location of the schema.
+pub fn lint_strict_schema(schema: &Value, pointer: &str) -> Vec<SchemaDiagnostic> {
+    let mut linter = Linter::default();
+    let is_object = schema.get("type") == Some(&Value::from("object"));
+    if !is_object || schema.get("anyOf").is_some() {
+        linter.report(pointer, SchemaProblem::RootNotObject);
+    }
+    linter.lint(schema, pointer.to_string(), 0);
+
+    if linter.properties > MAX_PROPERTIES {
+        linter.report(
+            pointer,
+            SchemaProblem::TooManyProperties {
+                count: linter.properties,
+            },
+        );
+    }
+    if linter.enum_values > MAX_ENUM_VALUES {
+        linter.report(
+            pointer,
+            SchemaProblem::TooManyEnumValues {
+                count: linter.enum_values,
+            },
+        );
+    }
+    if linter.string_length > MAX_TOTAL_STRING_LENGTH {
+        linter.report(
+            pointer,
+            SchemaProblem::StringsTooLong {
+                length: linter.string_length,
+            },
+        );
+    }
+    linter.diagnostics
+}
+
+#[derive(Default)]
+struct Linter {
+    diagnostics: Vec<SchemaDiagnostic>,
+    properties: usize,
+    enum_values: usize,
+    string_length: usize,
+}
+
+impl Linter {
+    fn report(&mut self, pointer: &str, problem: SchemaProblem) {
+        self.diagnostics.push(SchemaDiagnostic {
+            pointer: pointer.to_string(),
+            problem,
+        });
+    }
+
+    /// Lints a subschema nested in `depth` objects.
+    fn lint(&mut self, schema: &Value, pointer: String, depth: usize) {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(true) => return self.report(&pointer, SchemaProblem::ArbitraryValue),
+            _ => return,
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if !reference.starts_with('#') {
+                self.report(&pointer, SchemaProblem::ExternalRef(reference.to_string()));
+            }
+        }
+        for keyword in UNSUPPORTED_KEYWORDS {
+            if schema.contains_key(*keyword) {
+                self.report(
+                    &pointer,
+                    SchemaProblem::UnsupportedKeyword(keyword.to_string()),
+                );
+            }
+        }
+
+        let mut depth = depth;
+        if is_object_schema(schema) {
+            depth += 1;
+            if depth == MAX_NESTING_DEPTH + 1 {
+                self.report(&pointer, SchemaProblem::TooDeep { depth });
+            }
+            self.lint_object(schema, &pointer);
+        }
+
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            self.enum_values += values.len();
+            let length: usize = values.iter().map(string_length).sum();
+            self.string_length += length;
+            if values.len() > LARGE_ENUM_VALUES && length > MAX_LARGE_ENUM_LENGTH {
+                self.report(&pointer, SchemaProblem::LargeEnumTooLong { length });
+            }
+        }
+        if let Some(value) = schema.get("const") {
+            self.string_length += string_length(value);
+        }
+
+        for key in ["$defs", "definitions"] {
+            if let Some(Value::Object(definitions)) = schema.get(key) {
+                for (name, definition) in definitions {
+                    self.string_length += name.len();
+                    let pointer = format!("{pointer}/{key}/{}", escape(name));
+                    self.lint(definition, pointer, depth);
+                }
+            }
+        }
+        if let Some(Value::Object(properties)) = schema.get("properties") {
+            for (name, property) in properties {
+                let pointer = format!("{pointer}/properties/{}", escape(name));
+                self.lint(property, pointer, depth);
+            }
+        }
+        match schema.get("items") {
+            Some(Value::Array(items)) => {
+                for (index, item) in items.iter().enumerate() {
+                    self.lint(item, format!("{pointer}/items/{index}"), depth);
+                }
+            }
+            Some(items) => self.lint(items, format!("{pointer}/items"), depth),
+            None => {}
+        }
+        if let Some(Value::Array(variants)) = schema.get("anyOf") {
+            for (index, variant) in variants.iter().enumerate() {
+                self.lint(variant, format!("{pointer}/anyOf/{index}"), depth);
+            }
+        }
+    }
+
+    fn lint_object(&mut self, schema: &Map<String, Value>, pointer: &str) {
+        match schema.get("additionalProperties") {
+            Some(Value::Bool(false)) => {}
+            Some(Value::Object(_)) => self.report(pointer, SchemaProblem::OpenObject),
+            _ => self.report(pointer, SchemaProblem::AdditionalPropertiesNotFalse),
+        }
+        let Some(Value::Object(properties)) = schema.get("properties") else {
+            return;
+        };
+        self.properties += properties.len();
+        let required: Vec<&str> = match schema.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        for name in properties.keys() {
+            self.string_length += name.len();
+            if !required.contains(&name.as_str()) {
+                self.report(pointer, SchemaProblem::NotRequired(name.clone()));
+            }
+        }
+    }
+}
+
+fn is_object_schema(schema: &Map<String, Value>) -> bool {
+    match schema.get("type") {
+        Some(Value::String(r#type)) => r#type == "object",
+        Some(Value::Array(types)) => types.iter().any(|t| t == "object"),
+        _ => schema.contains_key("properties"),
+    }
+}
+
+fn string_length(value: &Value) -> usize {
+    match value {
+        Value::String(string) => string.len(),
+        _ => 0,
+    }
+}
+
+/// Escapes a key as a JSON pointer token.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::structured_output::{JsonSchemaStyle, ToolCallFunctionDefinition};
+    use crate::chat::{
+        ChatCompletion, ChatCompletionBuilderError, ChatCompletionResponseFormat,
+        ChatCompletionTool,
+    };
+    use schemars::JsonSchema;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Inventory {
+        items: Vec<Item>,
+        attributes: HashMap<String, Value>,
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Item {
+        name: String,
+        note: Option<String>,
+    }
+
+    fn nested(depth: usize) -> Value {
+        let mut schema = json!({ "type": "string" });
+        for _ in 0..depth {
+            schema = json!({
+                "type": "object",
+                "properties": { "a/b": schema },
+                "required": ["a/b"],
+                "additionalProperties": false
+            });
+        }
+        schema
+    }
+
+    #[test]
+    fn lint_schemas() {
+        let format =
+            ChatCompletionResponseFormatJsonSchema::new::<Item>(true, JsonSchemaStyle::OpenAI);
+        assert_eq!(format.lint(), []);
+        let format =
+            ChatCompletionResponseFormatJsonSchema::new::<Inventory>(true, JsonSchemaStyle::OpenAI);
+        assert_eq!(
+            format.lint(),
+            [SchemaDiagnostic {
+                pointer: "/schema/properties/attributes/items/properties/value".to_string(),
+                problem: SchemaProblem::ArbitraryValue,
+            }]
+        );
+
+        let function = ToolCallFunctionDefinition {
+            name: "get weather".to_string(),
+            description: None,
+            parameters: Some(json!({
+                "anyOf": [{ "type": "object" }],
+                "properties": {
+                    "city": { "type": "string", "pattern": "^[A-Z]", "minLength": 1 }
+                },
+            })),
+            strict: Some(true),
+        };
+        let diagnostics: Vec<String> = function.lint().iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "/name: invalid name `get weather`, must be 1 to 64 characters of a-z, A-Z, 0-9, `_` or `-`",
+                "/parameters: the root must be an object",
+                "/parameters: `additionalProperties` must be `false`",
+                "/parameters: property `city` must be required, make it nullable to be optional",
+                "/parameters/properties/city: unsupported keyword `minLength`",
+                "/parameters/anyOf/0: `additionalProperties` must be `false`",
+            ]
+        );
+
+        assert_eq!(lint_strict_schema(&nested(MAX_NESTING_DEPTH), ""), []);
+        let diagnostics = lint_strict_schema(&nested(MAX_NESTING_DEPTH + 2), "");
+        assert_eq!(
+            diagnostics,
+            [SchemaDiagnostic {
+                pointer: "/properties/a~1b".repeat(MAX_NESTING_DEPTH),
+                problem: SchemaProblem::TooDeep { depth: 11 },
+            }]
+        );
+
+        let values: Vec<String> = (0..300).map(|i| format!("{i:0>60}")).collect();
+        let schema = json!({
+            "type": "object",
+            "properties": { "value": { "type": "string", "enum": values } },
+            "required": ["value"],
+            "additionalProperties": false
+        });
+        assert_eq!(
+            lint_strict_schema(&schema, "")[0].problem,
+            SchemaProblem::LargeEnumTooLong { length: 18_000 }
+        );
+    }
+
+    #[test]
+    fn lint_type_finds_flattened_maps() {
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct WithFlatMap {
+            a: u32,
+            #[serde(flatten)]
+            extra: HashMap<String, Value>,
+        }
+
+        #[derive(JsonSchema)]
+        #[serde(deny_unknown_fields)]
+        #[allow(dead_code)]
+        struct Closed {
+            at: String,
+        }
+
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct Outer {
+            closed: Closed,
+            items: Vec<WithFlatMap>,
+        }
+
+        let diagnostics: Vec<String> = lint_type::<Outer>()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "/schema/properties/items/items: `additionalProperties` must be `false`",
+                "/schema/properties/items/items: `#[serde(flatten)]` map fields are not supported, use a map field without `flatten`",
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_lints_strict_schemas() {
+        let format =
+            ChatCompletionResponseFormat::json_schema::<Inventory>(true, JsonSchemaStyle::OpenAI);
+        // Other providers accept other schemas, so nothing is checked by default.
+        assert!(ChatCompletion::builder("gpt-4o-mini", [])
+            .response_format(format.clone())
+            .build()
+            .is_ok());
+        let error = ChatCompletion::builder("gpt-4o-mini", [])
+            .response_format(format)
+            .lint_strict_schemas(true)
+            .build()
+            .unwrap_err();
+        let ChatCompletionBuilderError::ValidationError(message) = error else {
+            panic!("expected a validation error, got {error:?}");
+        };
+        assert_eq!(
+            message,
+            "Invalid strict schema:\n/response_format/json_schema/schema/properties/attributes/items/properties/value: arbitrary values, like `serde_json::Value`, are not supported, use a typed value"
+        );
+
+        let format =
+            ChatCompletionResponseFormat::json_schema::<Inventory>(false, JsonSchemaStyle::OpenAI);
+        // A hand-written schema, which would only be valid without strict mode.
+        let mut tool = ChatCompletionTool::Function {
+            function: ToolCallFunctionDefinition {
+                name: "lookup".to_string(),
+                description: None,
+                parameters: Some(json!({
+                    "type": "object",
+                    "properties": { "sku": { "type": "string" } },
+                    "required": ["sku"]
+                })),
+                strict: None,
+            },
+        };
+        assert!(ChatCompletion::builder("gpt-4o-mini", [])
+            .response_format(format)
+            .tools([tool.clone()])
+            .lint_strict_schemas(true)
+            .build()
+            .is_ok());
+        let ChatCompletionTool::Function { function } = &mut tool;
+        function.strict = Some(true);
+        let error = ChatCompletion::builder("gpt-4o-mini", [])
+            .tools([tool])
+            .lint_strict_schemas(true)
+            .build()
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("\n/tools/0/function/parameters: `additionalProperties` must be `false`"));
+    }
+}
@@ -0,0 +1,356 @@
+//! List, retrieve, update and delete the chat completions created with `store` enabled.
+//!
+//! See the [Chat Completions API](https://platform.openai.com/docs/api-reference/chat/list)
+//! for more information.
+//!
+//! ```no_run
+//! use openai::chat::stored::StoredChatCompletion;
+//! use openai::{ApiResponseOrError, Credentials};
+//!
+//! # async fn run() -> ApiResponseOrError<()> {
+//! let credentials = Credentials::from_env();
+//! let completions = StoredChatCompletion::list_builder()
+//!     .model("gpt-4o-mini")
+//!     .metadata([("env".to_string(), "production".to_string())])
+//!     .credentials(credentials.clone())
+//!     .list()
+//!     .await?;
+//! for completion in &completions.data {
+//!     let messages = StoredChatCompletion::messages_builder(&completion.completion.id)
+//!         .credentials(credentials.clone())
+//!         .list()
+//!         .await?;
+//!     println!("{}: {} messages", completion.completion.id, messages.data.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+
+use derive_builder::Builder;
+use reqwest::Method;
+use serde::Deserialize;
+
+use super::{ChatCompletion, ChatCompletionMessage};
+use crate::{
+    openai_delete, openai_get, openai_post, openai_request_json, ApiResponseOrError, Credentials,
+};
+
+/// A chat completion created with `store` enabled.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredChatCompletion {
+    #[serde(flatten)]
+    pub completion: ChatCompletion,
+    /// The metadata set when creating or updating the completion.
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// A page of stored chat completions.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredChatCompletionList {
+    pub data: Vec<StoredChatCompletion>,
+    pub first_id: Option<String>,
+    /// Pass as `after` to get the next page.
+    pub last_id: Option<String>,
+    pub has_more: bool,
+}
+
+/// A message of the request of a stored chat completion.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredChatCompletionMessage {
+    pub id: String,
+    #[serde(flatten)]
+    pub message: ChatCompletionMessage,
+}
+
+/// A page of the messages of a stored chat completion.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredChatCompletionMessageList {
+    pub data: Vec<StoredChatCompletionMessage>,
+    pub first_id: Option<String>,
+    /// Pass as `after` to get the next page.
+    pub last_id: Option<String>,
+    pub has_more: bool,
+}
+
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct DeletedStoredChatCompletion {
+    pub id: String,
+    pub object: String,
+    pub deleted: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ListOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Builder, Debug, Clone)]
+#[builder(pattern = "owned")]
+#[builder(name = "StoredChatCompletionListBuilder")]
+#[builder(setter(strip_option, into))]
+pub struct StoredChatCompletionListRequest {
+    /// Only list the completions of this model.
+    #[builder(default)]
+    model: Option<String>,
+    /// Only list the completions with all of these metadata pairs.
+    #[builder(default)]
+    metadata: HashMap<String, String>,
+    /// The id of the last completion of the previous page.
+    #[builder(default)]
+    after: Option<String>,
+    /// The number of completions to return, 20 by default.
+    #[builder(default)]
+    limit: Option<u32>,
+    /// The order by creation time, ascending by default.
+    #[builder(default)]
+    order: Option<ListOrder>,
+    /// The credentials to use for this request.
+    #[builder(default)]
+    credentials: Option<Credentials>,
+}
+
+#[derive(Builder, Debug, Clone)]
+#[builder(pattern = "owned")]
+#[builder(name = "StoredChatCompletionMessagesBuilder")]
+#[builder(setter(strip_option, into))]
+pub struct StoredChatCompletionMessagesRequest {
+    completion_id: String,
+    /// The id of the last message of the previous page.
+    #[builder(default)]
+    after: Option<String>,
+    /// The number of messages to return, 20 by default.
+    #[builder(default)]
+    limit: Option<u32>,
+    /// The order by position in the conversation, ascending by default.
+    #[builder(default)]
+    order: Option<ListOrder>,
+    /// The credentials to use for this request.
+    #[builder(default)]
+    credentials: Option<Credentials>,
+}
+
+fn page_query(
+    after: Option<String>,
+    limit: Option<u32>,
+    order: Option<ListOrder>,
+) -> Vec<(String, String)> {
+    let mut query = Vec::new();
+    if let Some(after) = after {
+        query.push(("after".to_string(), after));
+    }
+    if let Some(limit) = limit {
+        query.push(("limit".to_string(), limit.to_string()));
+    }
+    if let Some(order) = order {
+        let order = match order {
+            ListOrder::Asc => "asc",
+            ListOrder::Desc => "desc",
+        };
+        query.push(("order".to_string(), order.to_string()));
+    }
+    query
+}
+
+impl StoredChatCompletion {
+    /// New StoredChatCompletionListBuilder
+    pub fn list_builder() -> StoredChatCompletionListBuilder {
+        StoredChatCompletionListBuilder::create_empty()
+    }
+
+    /// New StoredChatCompletionMessagesBuilder, for the messages of the completion `id`.
+    pub fn messages_builder(id: &str) -> StoredChatCompletionMessagesBuilder {
+        StoredChatCompletionMessagesBuilder::create_empty().completion_id(id)
+    }
+
+    /// Get a stored chat completion by id.
+    pub async fn fetch(id: &str, credentials: Credentials) -> ApiResponseOrError<Self> {
+        openai_get(
+            format!("chat/completions/{}", id).as_str(),
+            Some(credentials),
+        )
+        .await
+    }
+
+    /// Replace the metadata of a stored chat completion.
+    pub async fn update_metadata(
+        id: &str,
+        metadata: HashMap<String, String>,
+        credentials: Credentials,
+    ) -> ApiResponseOrError<Self> {
+        let body = serde_json::json!({ "metadata": metadata });
+        openai_post(
+            format!("chat/completions/{}", id).as_str(),
+            &body,
+            Some(credentials),
+        )
+        .await
+    }
+
+    /// Delete a stored chat completion by id.
+    pub async fn delete(
+        id: &str,
+        credentials: Credentials,
+    ) -> ApiResponseOrError<DeletedStoredChatCompletion> {
+        openai_delete(
+            format!("chat/completions/{}", id).as_str(),
+            Some(credentials),
+        )
+        .await
+    }
+
+    async fn list(
+        request: StoredChatCompletionListRequest,
+    ) -> ApiResponseOrError<StoredChatCompletionList> {
+        let mut query = Vec::new();
+        if let Some(model) = request.model {
+            query.push(("model".to_string(), model));
+        }
+        let mut metadata: Vec<_> = request.metadata.into_iter().collect();
+        metadata.sort();
+        for (key, value) in metadata {
+            query.push((format!("metadata[{key}]"), value));
+        }
+        query.extend(page_query(request.after, request.limit, request.order));
+        openai_request_json(
+            Method::GET,
+            "chat/completions",
+            |builder| builder.query(&query),
+            request.credentials,
+        )
+        .await
+    }
+
+    async fn list_messages(
+        request: StoredChatCompletionMessagesRequest,
+    ) -> ApiResponseOrError<StoredChatCompletionMessageList> {
+        let query = page_query(request.after, request.limit, request.order);
+        openai_request_json(
+            Method::GET,
+            format!("chat/completions/{}/messages", request.completion_id).as_str(),
+            |builder| builder.query(&query),
+            request.credentials,
+        )
+        .await
+    }
+}
+
+impl StoredChatCompletionListBuilder {
+    /// List the stored chat completions matching the filters.
+    pub async fn list(self) -> ApiResponseOrError<StoredChatCompletionList> {
+        StoredChatCompletion::list(self.build().unwrap()).await
+    }
+}
+
+impl StoredChatCompletionMessagesBuilder {
+    /// List the messages of the request of a stored chat completion.
+    pub async fn list(self) -> ApiResponseOrError<StoredChatCompletionMessageList> {
+        StoredChatCompletion::list_messages(self.build().unwrap()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatCompletionMessageRole;
+    use crate::tests::{json_response, serve_responses_recording};
+    use serde_json::json;
+
+    fn completion_json() -> serde_json::Value {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gpt-4o-mini",
+            "choices": [{ "index": 0, "finish_reason": "stop", "message": { "role": "assistant", "content": "Hi" } }],
+            "metadata": { "env": "production" }
+        })
+    }
+
+    #[tokio::test]
+    async fn stored_completions() {
+        let list = json!({
+            "object": "list",
+            "data": [completion_json()],
+            "first_id": "chatcmpl-1",
+            "last_id": "chatcmpl-1",
+            "has_more": false
+        });
+        let messages = json!({
+            "object": "list",
+            "data": [{ "id": "chatcmpl-1-0", "role": "user", "content": "Hello" }],
+            "first_id": "chatcmpl-1-0",
+            "last_id": "chatcmpl-1-0",
+            "has_more": false
+        });
+        let deleted =
+            json!({ "object": "chat.completion.deleted", "id": "chatcmpl-1", "deleted": true });
+        let (credentials, requests) = serve_responses_recording(vec![
+            json_response(&list.to_string()),
+            json_response(&messages.to_string()),
+            json_response(&completion_json().to_string()),
+            json_response(&completion_json().to_string()),
+            json_response(&deleted.to_string()),
+        ])
+        .await;
+
+        let list = StoredChatCompletion::list_builder()
+            .model("gpt-4o-mini")
+            .metadata([("env".to_string(), "production".to_string())])
+            .limit(10u32)
+            .order(ListOrder::Desc)
+            .credentials(credentials.clone())
+            .list()
+            .await
+            .unwrap();
+        assert_eq!(list.data[0].completion.id, "chatcmpl-1");
+        assert_eq!(list.data[0].metadata.as_ref().unwrap()["env"], "production");
+
+        let messages = StoredChatCompletion::messages_builder("chatcmpl-1")
+            .after("chatcmpl-0")
+            .credentials(credentials.clone())
+            .list()
+            .await
+            .unwrap();
+        assert_eq!(messages.data[0].id, "chatcmpl-1-0");
+        assert_eq!(
+            messages.data[0].message.role,
+            ChatCompletionMessageRole::User
+        );
+
+        let fetched = StoredChatCompletion::fetch("chatcmpl-1", credentials.clone())
+            .await
+            .unwrap();
+        assert_eq!(fetched.completion.id, "chatcmpl-1");
+        assert_eq!(fetched.metadata.unwrap()["env"], "production");
+
+        let updated = StoredChatCompletion::update_metadata(
+            "chatcmpl-1",
+            HashMap::from([("env".to_string(), "production".to_string())]),
+            credentials.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated.completion.choices[0].finish_reason, "stop");
+        assert!(
+            StoredChatCompletion::delete("chatcmpl-1", credentials)
+                .await
+                .unwrap()
+                .deleted
+        );
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "GET /v1/chat/completions?model=gpt-4o-mini&metadata%5Benv%5D=production&limit=10&order=desc HTTP/1.1",
+                "GET /v1/chat/completions/chatcmpl-1/messages?after=chatcmpl-0 HTTP/1.1",
+                "GET /v1/chat/completions/chatcmpl-1 HTTP/1.1",
+                "POST /v1/chat/completions/chatcmpl-1 HTTP/1.1",
+                "DELETE /v1/chat/completions/chatcmpl-1 HTTP/1.1",
+            ]
+        );
+    }
+}
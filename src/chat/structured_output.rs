@@ -1,18 +1,251 @@
+use std::collections::BTreeMap;
 use std::mem::take;
 
 use schemars::{
-    schema::{Schema, SchemaObject},
-    schema_for,
+    r#gen::SchemaSettings,
+    schema::{
+        InstanceType, ObjectValidation, Schema, SchemaObject, SingleOrVec, SubschemaValidation,
+    },
     visit::{visit_schema_object, Visitor},
     JsonSchema,
 };
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+use super::validator::SchemaViolation;
+use super::{
+    ChatCompletion, ChatCompletionBuilder, ChatCompletionChoice, ChatCompletionMessage,
+    ChatCompletionMessageRole, ChatCompletionResponseFormat,
+};
+use crate::{ApiResponseOrError, OpenAiError};
+
+/// The provider a generated JSON Schema targets, as each supports a different subset
+/// of JSON Schema.
+#[derive(Clone, Copy)]
 pub enum JsonSchemaStyle {
+    /// OpenAI structured outputs: every property is required, no additional properties,
+    /// and no validation keywords.
     OpenAI,
+    /// Grok structured outputs: no validation keywords.
     Grok,
+    /// The OpenAPI subset of Gemini `responseSchema`: `nullable` instead of null
+    /// unions, no `additionalProperties` and no `const`.
+    Gemini,
+    /// The `input_schema` of Anthropic tools, which supports plain JSON Schema.
+    Anthropic,
+    /// The `format` of Ollama, converted to a llama.cpp grammar which only enforces
+    /// the `date`, `time`, `date-time` and `uuid` string formats.
+    Ollama,
+    /// The `guided_json` of vLLM guided decoding, which supports plain JSON Schema.
+    Vllm,
+    /// A user-defined transform, to target other providers.
+    Custom(&'static dyn SchemaTransform),
+}
+
+impl std::fmt::Debug for JsonSchemaStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonSchemaStyle::OpenAI => write!(f, "OpenAI"),
+            JsonSchemaStyle::Grok => write!(f, "Grok"),
+            JsonSchemaStyle::Gemini => write!(f, "Gemini"),
+            JsonSchemaStyle::Anthropic => write!(f, "Anthropic"),
+            JsonSchemaStyle::Ollama => write!(f, "Ollama"),
+            JsonSchemaStyle::Vllm => write!(f, "Vllm"),
+            JsonSchemaStyle::Custom(transform) => write!(f, "Custom({:?})", transform.name()),
+        }
+    }
+}
+
+/// Custom styles are equal if their transforms have the same name.
+impl PartialEq for JsonSchemaStyle {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (JsonSchemaStyle::Custom(a), JsonSchemaStyle::Custom(b)) => a.name() == b.name(),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+}
+
+impl Eq for JsonSchemaStyle {}
+
+/// Options of the schema generation, besides the style.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct SchemaOptions {
+    /// Emit every named type as a definition in `$defs`, referenced with `$ref`,
+    /// instead of inlining it, e.g. to shrink schemas reusing a type many times.
+    ///
+    /// Schemas of recursive types, like trees, always use definitions.
+    pub definitions: bool,
+}
+
+/// Adapts the JSON Schema generated for a type to the subset a provider supports.
+///
+/// ```
+/// use openai::chat::structured_output::{generate_json_schema, JsonSchemaStyle, SchemaTransform};
+/// use schemars::schema::SchemaObject;
+///
+/// /// Strips the descriptions, to save tokens.
+/// struct NoDescriptions;
+///
+/// impl SchemaTransform for NoDescriptions {
+///     fn name(&self) -> &str {
+///         "no-descriptions"
+///     }
+///
+///     fn transform_object(&self, schema: &mut SchemaObject) {
+///         JsonSchemaStyle::OpenAI.transform_object(schema);
+///         schema.metadata().description = None;
+///     }
+/// }
+///
+/// /// The answer
+/// #[derive(schemars::JsonSchema)]
+/// struct Answer {
+///     /// The value
+///     value: String,
+/// }
+///
+/// let (schema, _) = generate_json_schema::<Answer>(JsonSchemaStyle::Custom(&NoDescriptions));
+/// assert!(schema["properties"]["value"].get("description").is_none());
+/// ```
+pub trait SchemaTransform: Send + Sync {
+    /// The name of the provider, which identifies the transform.
+    fn name(&self) -> &str;
+
+    /// The settings of the schema generator, e.g. how to represent an `Option`.
+    fn settings(&self) -> SchemaSettings {
+        let mut settings = SchemaSettings::default();
+        settings.option_nullable = false;
+        settings.inline_subschemas = true;
+        settings
+    }
+
+    /// Transforms every schema object, before its subschemas.
+    fn transform_object(&self, _schema: &mut SchemaObject) {}
+
+    /// The validation keywords to strip from every subschema. They are described in
+    /// the `description` of their subschema instead, and kept as
+    /// [`StrippedConstraints`] to check outputs locally.
+    fn stripped_keywords(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Whether to rewrite the shapes strict modes reject into equivalent ones, like
+    /// maps into arrays of entries. See [`ResponseAdapter`].
+    fn rewrite_shapes(&self) -> bool {
+        false
+    }
+
+    /// Transforms the complete schema, after every object and the stripping of
+    /// constraints.
+    fn transform_value(&self, _schema: &mut Value) {}
+}
+
+impl SchemaTransform for JsonSchemaStyle {
+    fn name(&self) -> &str {
+        match self {
+            JsonSchemaStyle::OpenAI => "openai",
+            JsonSchemaStyle::Grok => "grok",
+            JsonSchemaStyle::Gemini => "gemini",
+            JsonSchemaStyle::Anthropic => "anthropic",
+            JsonSchemaStyle::Ollama => "ollama",
+            JsonSchemaStyle::Vllm => "vllm",
+            JsonSchemaStyle::Custom(transform) => transform.name(),
+        }
+    }
+
+    fn settings(&self) -> SchemaSettings {
+        let mut settings = SchemaSettings::default();
+        settings.option_nullable = false;
+        settings.inline_subschemas = true;
+        match self {
+            JsonSchemaStyle::Grok => settings.option_add_null_type = false,
+            JsonSchemaStyle::Gemini => {
+                settings.option_nullable = true;
+                settings.option_add_null_type = false;
+            }
+            JsonSchemaStyle::Custom(transform) => return transform.settings(),
+            _ => {}
+        }
+        settings
+    }
+
+    fn transform_object(&self, schema: &mut SchemaObject) {
+        match self {
+            JsonSchemaStyle::OpenAI | JsonSchemaStyle::Grok => {
+                if let Some(sub) = &mut schema.subschemas {
+                    one_of_to_any_of(sub);
+                }
+                if let Some(sub) = &mut schema.object {
+                    if *self == JsonSchemaStyle::OpenAI {
+                        if sub.additional_properties.is_none() {
+                            sub.additional_properties = Some(Box::new(Schema::Bool(false)));
+                        }
+                        sub.required = sub.properties.keys().cloned().collect();
+                    }
+                }
+            }
+            JsonSchemaStyle::Gemini => {
+                if let Some(sub) = &mut schema.subschemas {
+                    one_of_to_any_of(sub);
+                }
+                if let Some(value) = schema.const_value.take() {
+                    schema.enum_values = Some(vec![value]);
+                }
+                let format = schema.format.as_deref();
+                if !matches!(
+                    format,
+                    Some("date-time" | "enum" | "int32" | "int64" | "float" | "double")
+                ) {
+                    schema.format = None;
+                }
+                if let Some(sub) = &mut schema.object {
+                    sub.additional_properties = None;
+                }
+            }
+            JsonSchemaStyle::Ollama => {
+                let format = schema.format.as_deref();
+                if !matches!(format, Some("date" | "time" | "date-time" | "uuid")) {
+                    schema.format = None;
+                }
+            }
+            JsonSchemaStyle::Anthropic | JsonSchemaStyle::Vllm => {}
+            JsonSchemaStyle::Custom(transform) => transform.transform_object(schema),
+        }
+    }
+
+    fn stripped_keywords(&self) -> &[&'static str] {
+        match self {
+            JsonSchemaStyle::OpenAI | JsonSchemaStyle::Grok => &[
+                "minimum",
+                "maximum",
+                "exclusiveMinimum",
+                "exclusiveMaximum",
+                "multipleOf",
+                "minLength",
+                "maxLength",
+                "pattern",
+                "format",
+            ],
+            JsonSchemaStyle::Gemini => &["exclusiveMinimum", "exclusiveMaximum", "multipleOf"],
+            JsonSchemaStyle::Custom(transform) => transform.stripped_keywords(),
+            _ => &[],
+        }
+    }
+
+    fn rewrite_shapes(&self) -> bool {
+        match self {
+            JsonSchemaStyle::OpenAI | JsonSchemaStyle::Grok | JsonSchemaStyle::Gemini => true,
+            JsonSchemaStyle::Custom(transform) => transform.rewrite_shapes(),
+            _ => false,
+        }
+    }
+
+    fn transform_value(&self, schema: &mut Value) {
+        if let JsonSchemaStyle::Custom(transform) = self {
+            transform.transform_value(schema);
+        }
+    }
 }
 
 #[derive(Serialize, Debug, Clone, Eq, PartialEq)]
@@ -37,13 +270,33 @@ pub struct ChatCompletionResponseFormatJsonSchema {
 
 impl ChatCompletionResponseFormatJsonSchema {
     pub fn new<T: JsonSchema>(strict: bool, json_style: JsonSchemaStyle) -> Self {
-        let (schema, description) = generate_json_schema::<T>(json_style);
-        ChatCompletionResponseFormatJsonSchema {
-            name: T::schema_name(),
+        Self::with_side_table::<T>(strict, json_style).0
+    }
+
+    /// Like [`Self::new`], also returning what the style changed in the schema, to
+    /// check and deserialize outputs.
+    pub fn with_side_table<T: JsonSchema>(
+        strict: bool,
+        json_style: JsonSchemaStyle,
+    ) -> (Self, SchemaSideTable) {
+        Self::with_options::<T>(strict, json_style, SchemaOptions::default())
+    }
+
+    /// Like [`Self::with_side_table`], with options like emitting `$defs`.
+    pub fn with_options<T: JsonSchema>(
+        strict: bool,
+        json_style: JsonSchemaStyle,
+        options: SchemaOptions,
+    ) -> (Self, SchemaSideTable) {
+        let (schema, description, side_table) =
+            generate_json_schema_with_options::<T>(json_style, options);
+        let format = ChatCompletionResponseFormatJsonSchema {
+            name: sanitize_name(&T::schema_name()),
             description,
             schema: Some(schema),
             strict: Some(strict),
-        }
+        };
+        (format, side_table)
     }
 }
 
@@ -73,75 +326,1634 @@ pub struct ToolCallFunctionDefinition {
 impl ToolCallFunctionDefinition {
     /// Create a new ToolCallFunctionDefinition with the given strictness and JSON Schema style.
     ///
+    /// The parameters are generated like a response format, see [`generate_json_schema`],
+    /// and the name of `T` is sanitized with [`sanitize_name`].
+    ///
     /// Note: Grok tools does not support strict schema adherence, need to set `strict` to None.
-    pub fn new<T: JsonSchema>(strict: Option<bool>) -> Self {
-        let schema = schema_for!(T);
-        let description = if let Some(metadata) = &schema.schema.metadata {
-            metadata.description.clone()
-        } else {
-            None
-        };
-        ToolCallFunctionDefinition {
+    pub fn new<T: JsonSchema>(strict: Option<bool>, json_style: JsonSchemaStyle) -> Self {
+        Self::with_side_table::<T>(strict, json_style).0
+    }
+
+    /// Like [`Self::new`], also returning what the style changed in the schema, to
+    /// check and deserialize the arguments.
+    pub fn with_side_table<T: JsonSchema>(
+        strict: Option<bool>,
+        json_style: JsonSchemaStyle,
+    ) -> (Self, SchemaSideTable) {
+        let (parameters, description, side_table) =
+            generate_json_schema_with_options::<T>(json_style, SchemaOptions::default());
+        let definition = ToolCallFunctionDefinition {
             description,
-            name: T::schema_name(),
-            parameters: Some(json!(schema)),
+            name: sanitize_name(&T::schema_name()),
+            parameters: Some(parameters),
             strict,
+        };
+        (definition, side_table)
+    }
+}
+
+/// Makes a valid function or response format name, by replacing the characters
+/// other than a-z, A-Z, 0-9, `_` and `-` with `_` and truncating it to 64 characters.
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .take(64)
+        .collect();
+    if name.is_empty() {
+        "_".to_string()
+    } else {
+        name
+    }
+}
+
+/// A chat completion whose choices were deserialized as `T`.
+#[derive(Debug)]
+pub struct ParsedChatCompletion<T> {
+    /// The raw completion.
+    pub completion: ChatCompletion,
+    /// The outcome for each choice, in the same order as `completion.choices`.
+    pub choices: Vec<Result<ParsedOutput<T>, ParseError>>,
+}
+
+/// The answer of the model to a structured output request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParsedOutput<T> {
+    /// The message content, deserialized as `T`.
+    Parsed(T),
+    /// The model declined to answer, with its explanation.
+    Refusal(String),
+}
+
+/// Why the content of a choice could not be deserialized.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The generation stopped at `max_completion_tokens` or the context length
+    /// (`finish_reason == "length"`), so the content is incomplete JSON.
+    Truncated { content: String },
+    /// The content was omitted by the content filter.
+    ContentFilter,
+    /// The message has no content, e.g. because the model called a tool instead.
+    MissingContent { finish_reason: String },
+    /// The content is not a valid `T`.
+    Deserialize {
+        error: serde_json::Error,
+        content: String,
+    },
+    /// The content violates constraints the schema style stripped from the schema,
+    /// see [`StrippedConstraints`].
+    Constraints {
+        violations: Vec<SchemaViolation>,
+        content: String,
+    },
+    /// The content does not match a schema defined at runtime, see
+    /// [`DynamicSchema`](super::dynamic_schema::DynamicSchema).
+    Schema {
+        violations: Vec<SchemaViolation>,
+        content: String,
+    },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Truncated { .. } => {
+                f.write_str("The output was truncated by the token limit")
+            }
+            ParseError::ContentFilter => {
+                f.write_str("The output was omitted by the content filter")
+            }
+            ParseError::MissingContent { finish_reason } => {
+                write!(
+                    f,
+                    "The message has no content (finish reason: {finish_reason})"
+                )
+            }
+            ParseError::Deserialize { error, .. } => {
+                write!(f, "Failed to deserialize the output: {error}")
+            }
+            ParseError::Constraints { violations, .. } => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(
+                    f,
+                    "The output violates the constraints of the schema: {}",
+                    violations.join(", ")
+                )
+            }
+            ParseError::Schema { violations, .. } => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(
+                    f,
+                    "The output does not match the schema: {}",
+                    violations.join(", ")
+                )
+            }
         }
     }
 }
 
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Deserialize { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The object a value is wrapped in when its schema root is not an object.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Wrapped<T> {
+    value: T,
+}
+
+impl<T: DeserializeOwned> ParsedOutput<T> {
+    /// Parses the message of a choice, checking for refusals and truncation first.
+    pub fn from_choice(choice: &ChatCompletionChoice) -> Result<Self, ParseError> {
+        let message = &choice.message;
+        if let Some(refusal) = &message.refusal {
+            return Ok(ParsedOutput::Refusal(refusal.clone()));
+        }
+        let content = message.content.as_ref().map(|content| content.to_text());
+        match (choice.finish_reason.as_str(), content) {
+            ("length", content) => Err(ParseError::Truncated {
+                content: content.unwrap_or_default(),
+            }),
+            ("content_filter", _) => Err(ParseError::ContentFilter),
+            (finish_reason, None) => Err(ParseError::MissingContent {
+                finish_reason: finish_reason.to_string(),
+            }),
+            (_, Some(content)) => match serde_json::from_str(&content) {
+                Ok(parsed) => Ok(ParsedOutput::Parsed(parsed)),
+                // A type whose schema root was wrapped, see `SchemaSideTable::wrapped`.
+                Err(error) => match serde_json::from_str::<Wrapped<T>>(&content) {
+                    Ok(Wrapped { value }) => Ok(ParsedOutput::Parsed(value)),
+                    Err(_) => Err(ParseError::Deserialize { error, content }),
+                },
+            },
+        }
+    }
+}
+
+impl ChatCompletionBuilder {
+    /// Requests a structured output following the schema of `T`, with strict
+    /// adherence in the OpenAI style, and deserializes every choice as `T`.
+    /// Choices violating the constraints stripped from the schema, like ranges,
+    /// are reported as [`ParseError::Constraints`].
+    ///
+    /// Replaces any `response_format` set on the builder.
+    pub async fn create_parsed<T: JsonSchema + DeserializeOwned>(
+        self,
+    ) -> ApiResponseOrError<ParsedChatCompletion<T>> {
+        let (json_schema, side_table) = ChatCompletionResponseFormatJsonSchema::with_side_table::<T>(
+            true,
+            JsonSchemaStyle::OpenAI,
+        );
+        let schema = json_schema.schema.clone().unwrap_or_default();
+        let completion = self
+            .response_format(ChatCompletionResponseFormat::JsonSchema { json_schema })
+            .create()
+            .await?;
+        let choices = completion
+            .choices
+            .iter()
+            .map(|choice| match ParsedOutput::<Value>::from_choice(choice)? {
+                ParsedOutput::Refusal(refusal) => Ok(ParsedOutput::Refusal(refusal)),
+                ParsedOutput::Parsed(value) => {
+                    let content = choice_text(choice);
+                    let parsed = match side_table.deserialize(&schema, value.clone()) {
+                        Ok(parsed) => parsed,
+                        Err(error) => return Err(ParseError::Deserialize { error, content }),
+                    };
+                    let violations = side_table.constraints.check(&schema, &value);
+                    if !violations.is_empty() {
+                        return Err(ParseError::Constraints {
+                            violations,
+                            content,
+                        });
+                    }
+                    Ok(ParsedOutput::Parsed(parsed))
+                }
+            })
+            .collect();
+        Ok(ParsedChatCompletion {
+            completion,
+            choices,
+        })
+    }
+
+    /// Requests an output of type `T` and validates it against the schema of `T`
+    /// generated in the given style, including the constraints the style stripped,
+    /// then deserializes it.
+    ///
+    /// When the first choice is invalid, its message is appended to the conversation
+    /// with a user message listing the problems, and the request is sent again, up
+    /// to `max_retries` times. Any `response_format` set on the builder is kept,
+    /// e.g. for `json_object` mode, otherwise it is set to the non-strict schema of `T`.
+    /// The model does not see the styled schema then, so the output is validated
+    /// against the plain schema of `T` instead, and deserialized as it is.
+    pub async fn create_validated<T: JsonSchema + DeserializeOwned>(
+        mut self,
+        json_style: JsonSchemaStyle,
+        max_retries: usize,
+    ) -> Result<ValidatedChatCompletion<T>, ValidationError> {
+        let (schema, full_schema, side_table) = if matches!(self.response_format, None | Some(None))
+        {
+            let (json_schema, side_table) =
+                ChatCompletionResponseFormatJsonSchema::with_side_table::<T>(false, json_style);
+            let schema = json_schema.schema.clone().expect("unreachable");
+            // Checks the stripped constraints too.
+            let full_schema = side_table.constraints.restore(&schema);
+            self = self.response_format(ChatCompletionResponseFormat::JsonSchema { json_schema });
+            (schema, full_schema, side_table)
+        } else {
+            let schema = serde_json::to_value(schemars::schema_for!(T)).expect("unreachable");
+            (schema.clone(), schema, SchemaSideTable::default())
+        };
+        let mut failed_attempts = Vec::new();
+        loop {
+            let completion = self.clone().create().await?;
+            let choice = completion
+                .choices
+                .first()
+                .ok_or(ValidationError::NoChoices)?;
+            let failure = match ParsedOutput::<Value>::from_choice(choice) {
+                Ok(ParsedOutput::Refusal(refusal)) => {
+                    return Err(ValidationError::Refusal(refusal))
+                }
+                Ok(ParsedOutput::Parsed(value)) => {
+                    let violations = super::validator::validate(&full_schema, &value);
+                    if violations.is_empty() {
+                        match side_table.deserialize(&schema, value) {
+                            Ok(value) => {
+                                return Ok(ValidatedChatCompletion {
+                                    value,
+                                    completion,
+                                    failed_attempts,
+                                })
+                            }
+                            Err(error) => AttemptFailure::Parse(ParseError::Deserialize {
+                                error,
+                                content: choice_text(choice),
+                            }),
+                        }
+                    } else {
+                        AttemptFailure::Schema(violations)
+                    }
+                }
+                Err(error) => AttemptFailure::Parse(error),
+            };
+
+            let message = choice.message.clone();
+            failed_attempts.push(FailedAttempt {
+                completion,
+                failure,
+            });
+            if failed_attempts.len() > max_retries {
+                return Err(ValidationError::Invalid(failed_attempts));
+            }
+            let failure = &failed_attempts.last().expect("unreachable").failure;
+            let messages = self.messages.get_or_insert_with(Vec::new);
+            messages.push(message);
+            messages.push(ChatCompletionMessage {
+                role: ChatCompletionMessageRole::User,
+                content: Some(failure.corrective_message().into()),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+pub(super) fn choice_text(choice: &ChatCompletionChoice) -> String {
+    let content = choice.message.content.as_ref();
+    content.map(|content| content.to_text()).unwrap_or_default()
+}
+
+/// An output validated against its schema and deserialized.
+#[derive(Debug)]
+pub struct ValidatedChatCompletion<T> {
+    pub value: T,
+    /// The completion of the valid output.
+    pub completion: ChatCompletion,
+    /// The attempts before the valid output, in order.
+    pub failed_attempts: Vec<FailedAttempt>,
+}
+
+/// An attempt whose output was invalid, which was asked again.
+#[derive(Debug)]
+pub struct FailedAttempt {
+    pub completion: ChatCompletion,
+    pub failure: AttemptFailure,
+}
+
+#[derive(Debug)]
+pub enum AttemptFailure {
+    /// The output is not JSON, was cut short, or does not deserialize as the type.
+    Parse(ParseError),
+    /// The output does not match the schema.
+    Schema(Vec<SchemaViolation>),
+}
+
+impl AttemptFailure {
+    /// The user message asking the model to fix its output.
+    fn corrective_message(&self) -> String {
+        let problems = match self {
+            AttemptFailure::Parse(error) => format!("- {error}"),
+            AttemptFailure::Schema(violations) => violations
+                .iter()
+                .map(|violation| format!("- {violation}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        format!(
+            "Your response is not valid:\n{problems}\nRespond again with only the corrected JSON."
+        )
+    }
+}
+
+impl std::fmt::Display for AttemptFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptFailure::Parse(error) => write!(f, "{error}"),
+            AttemptFailure::Schema(violations) => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                write!(f, "Schema violations: {}", violations.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    Api(OpenAiError),
+    /// The response has no choices.
+    NoChoices,
+    /// The model refused to answer, which is not retried.
+    Refusal(String),
+    /// Every attempt was invalid.
+    Invalid(Vec<FailedAttempt>),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Api(error) => write!(f, "API error: {error}"),
+            ValidationError::NoChoices => f.write_str("The response has no choices"),
+            ValidationError::Refusal(refusal) => write!(f, "The model refused: {refusal}"),
+            ValidationError::Invalid(attempts) => {
+                write!(f, "Invalid output after {} attempts", attempts.len())?;
+                if let Some(attempt) = attempts.last() {
+                    write!(f, ", the last one failed with: {}", attempt.failure)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ValidationError::Api(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<OpenAiError> for ValidationError {
+    fn from(value: OpenAiError) -> Self {
+        ValidationError::Api(value)
+    }
+}
+
 /// Generate a JSON Schema with the given style.
 ///
 /// IMPORTANT: Both OpenAI and Grok do not support the `format` and `minimum` JSON Schema attributes.
 /// As a result, numeric type constraints (like `u8`, `i32`, etc) cannot be enforced - all integers
-/// will be treated as `i64` and all floating point numbers as `f64`.
+/// will be treated as `i64` and all floating point numbers as `f64`. The model is told about the
+/// stripped constraints in the descriptions, see [`generate_json_schema_with_options`] to check them.
+///
+/// Named types are inlined, unless they are recursive: the schema then uses `$defs`.
+/// Types which are not objects, like a `Vec` or an enum, are wrapped in an object
+/// with a single `value` property, as the root of a schema must be an object.
 pub fn generate_json_schema<T: JsonSchema>(json_style: JsonSchemaStyle) -> (Value, Option<String>) {
-    let mut settings = schemars::r#gen::SchemaSettings::default();
-    settings.option_nullable = false;
-    settings.inline_subschemas = true;
-    settings.option_add_null_type = match json_style {
-        JsonSchemaStyle::OpenAI => true,
-        JsonSchemaStyle::Grok => false,
-    };
-    let mut generator = schemars::SchemaGenerator::new(settings);
+    let (schema, description, _) =
+        generate_json_schema_with_options::<T>(json_style, SchemaOptions::default());
+    (schema, description)
+}
+
+/// Generate a JSON Schema with the given style and options, with the constraints
+/// the style stripped from it.
+pub fn generate_json_schema_with_options<T: JsonSchema>(
+    json_style: JsonSchemaStyle,
+    options: SchemaOptions,
+) -> (Value, Option<String>, SchemaSideTable) {
+    let mut settings = json_style.settings();
+    settings.definitions_path = "#/$defs/".to_string();
+    if options.definitions {
+        settings.inline_subschemas = false;
+    }
+    let mut generator = schemars::SchemaGenerator::new(settings.clone());
     let mut schema = T::json_schema(&mut generator).into_object();
+    if settings.inline_subschemas && !generator.definitions().is_empty() {
+        // A recursive type was referenced instead of inlined forever.
+        settings.inline_subschemas = false;
+        generator = schemars::SchemaGenerator::new(settings);
+        schema = T::json_schema(&mut generator).into_object();
+    }
     let description = schema.metadata().description.clone();
+    let (schema, side_table) =
+        normalize_json_schema(schema, generator.take_definitions(), json_style);
+    (schema, description, side_table)
+}
+
+/// Runs a schema and its definitions through the pipeline of a style: rewriting
+/// shapes, wrapping a root which is not an object, transforming every object and
+/// stripping constraints.
+pub(crate) fn normalize_json_schema(
+    mut schema: SchemaObject,
+    mut definitions: schemars::Map<String, Schema>,
+    json_style: JsonSchemaStyle,
+) -> (Value, SchemaSideTable) {
+    if json_style.rewrite_shapes() {
+        let mut root = Schema::Object(schema);
+        rewrite_shapes_of(&mut root);
+        schema = root.into_object();
+        definitions.values_mut().for_each(rewrite_shapes_of);
+    }
+    let wrapped = !is_plain_object(&schema);
+    if wrapped {
+        schema = SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            object: Some(Box::new(ObjectValidation {
+                properties: [("value".to_string(), Schema::Object(schema))].into(),
+                required: ["value".to_string()].into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+    }
     let mut processor = SchemaPostProcessor { style: json_style };
     processor.visit_schema_object(&mut schema);
-    let schema = serde_json::to_value(schema).expect("unreachable");
-    (schema, description)
+    let mut schema = serde_json::to_value(schema).expect("unreachable");
+    if !definitions.is_empty() {
+        let definitions = definitions
+            .into_iter()
+            .map(|(name, mut definition)| {
+                processor.visit_schema(&mut definition);
+                (name, serde_json::to_value(definition).expect("unreachable"))
+            })
+            .collect();
+        schema["$defs"] = Value::Object(definitions);
+    }
+    let mut side_table = SchemaSideTable {
+        wrapped,
+        ..Default::default()
+    };
+    collect_shape_markers(&mut schema, String::new(), &mut side_table.adapter);
+    strip_constraints(
+        &mut schema,
+        json_style.stripped_keywords(),
+        String::new(),
+        &mut side_table.constraints,
+    );
+    json_style.transform_value(&mut schema);
+    (schema, side_table)
+}
+
+/// What the schema pipeline changed in a schema, to check and deserialize the
+/// outputs following it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SchemaSideTable {
+    pub constraints: StrippedConstraints,
+    pub adapter: ResponseAdapter,
+    /// Whether the root was wrapped in an object with a single `value` property,
+    /// not being an object.
+    pub wrapped: bool,
+}
+
+impl SchemaSideTable {
+    /// Whether outputs have to be converted before being deserialized.
+    pub fn changes_shape(&self) -> bool {
+        self.wrapped || !self.adapter.is_empty()
+    }
+
+    /// Converts an output following `schema` back to the shape of `T`, and
+    /// deserializes it.
+    pub fn deserialize<T: DeserializeOwned>(
+        &self,
+        schema: &Value,
+        value: Value,
+    ) -> Result<T, serde_json::Error> {
+        let mut value = self.adapter.adapt(schema, value);
+        if self.wrapped {
+            value = match value {
+                Value::Object(mut object) => object.remove("value").unwrap_or_default(),
+                value => value,
+            };
+        }
+        serde_json::from_value(value)
+    }
+}
+
+/// Whether a schema is an object, which can be the root of a schema.
+fn is_plain_object(schema: &SchemaObject) -> bool {
+    let is_object = matches!(
+        &schema.instance_type,
+        Some(SingleOrVec::Single(r#type)) if **r#type == InstanceType::Object
+    );
+    is_object
+        && schema.subschemas.is_none()
+        && schema.reference.is_none()
+        && schema.enum_values.is_none()
+        && schema.const_value.is_none()
+}
+
+/// The validation keywords stripped from a schema, by JSON pointer of their subschema.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StrippedConstraints(BTreeMap<String, Map<String, Value>>);
+
+impl StrippedConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The keywords stripped from the subschema at `pointer`.
+    pub fn get(&self, pointer: &str) -> Option<&Map<String, Value>> {
+        self.0.get(pointer)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Map<String, Value>)> {
+        self.0.iter()
+    }
+
+    /// Puts the stripped keywords back into the schema they were stripped from.
+    pub fn restore(&self, schema: &Value) -> Value {
+        let mut schema = schema.clone();
+        for (pointer, keywords) in &self.0 {
+            if let Some(Value::Object(subschema)) = schema.pointer_mut(pointer) {
+                subschema.extend(keywords.clone());
+            }
+        }
+        schema
+    }
+
+    /// Validates `instance` against the schema the constraints were stripped from,
+    /// with the constraints.
+    pub fn check(&self, schema: &Value, instance: &Value) -> Vec<SchemaViolation> {
+        super::validator::validate(&self.restore(schema), instance)
+    }
+}
+
+/// Strips `keywords` from every subschema, recording them and describing them in
+/// the `description`.
+fn strip_constraints(
+    schema: &mut Value,
+    keywords: &[&str],
+    pointer: String,
+    constraints: &mut StrippedConstraints,
+) {
+    if keywords.is_empty() {
+        return;
+    }
+    let Value::Object(object) = schema else {
+        return;
+    };
+    let stripped: Map<String, Value> = keywords
+        .iter()
+        .filter_map(|keyword| Some((keyword.to_string(), object.remove(*keyword)?)))
+        .collect();
+    if let Some(text) = describe_constraints(&stripped) {
+        let description = match object.get("description").and_then(Value::as_str) {
+            Some(description) => format!("{description} ({text})"),
+            None => format!("{}{}", text[..1].to_uppercase(), &text[1..]),
+        };
+        object.insert("description".to_string(), description.into());
+    }
+    if !stripped.is_empty() {
+        constraints.0.insert(pointer.clone(), stripped);
+    }
+
+    for (pointer, schema) in subschemas_mut(object, &pointer) {
+        strip_constraints(schema, keywords, pointer, constraints);
+    }
+}
+
+/// The subschemas of a schema object, with their JSON pointers.
+fn subschemas_mut<'a>(
+    object: &'a mut Map<String, Value>,
+    pointer: &str,
+) -> Vec<(String, &'a mut Value)> {
+    let mut subschemas = Vec::new();
+    for (key, value) in object.iter_mut() {
+        match (key.as_str(), value) {
+            ("properties" | "$defs" | "definitions", Value::Object(schemas)) => {
+                for (name, schema) in schemas {
+                    let name = name.replace('~', "~0").replace('/', "~1");
+                    subschemas.push((format!("{pointer}/{key}/{name}"), schema));
+                }
+            }
+            ("items" | "not" | "anyOf" | "oneOf" | "allOf", Value::Array(schemas)) => {
+                for (index, schema) in schemas.iter_mut().enumerate() {
+                    subschemas.push((format!("{pointer}/{key}/{index}"), schema));
+                }
+            }
+            ("items" | "additionalProperties" | "not", schema) => {
+                subschemas.push((format!("{pointer}/{key}"), schema));
+            }
+            _ => {}
+        }
+    }
+    subschemas
 }
 
+/// The extension marking a rewritten subschema until the [`ResponseAdapter`] is
+/// collected.
+const REWRITTEN_SHAPE: &str = "x-rewritten-shape";
+
+/// A shape rewritten into a strict-compatible one, which outputs have to be
+/// converted back from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RewrittenShape {
+    /// A map, like a `HashMap`, rewritten into an array of `{"key", "value"}` entries.
+    MapEntries,
+    /// A tuple, rewritten into an object with the properties `"0"`, `"1"`, ...
+    Tuple,
+}
+
+/// Converts outputs following a schema with rewritten shapes back to the shapes
+/// the Rust types deserialize from.
+///
+/// Strict modes reject maps, as objects with arbitrary keys, and tuples, as
+/// arrays with positional items. Styles rewriting shapes (see
+/// [`SchemaTransform::rewrite_shapes`]) generate them as arrays of entries and as
+/// objects instead, and enums flattened into a struct as variants with the fields
+/// of the struct. Flattened maps are dropped from the schema by `schemars` and are
+/// not supported.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ResponseAdapter(BTreeMap<String, RewrittenShape>);
+
+impl ResponseAdapter {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The shape the subschema at `pointer` was rewritten from.
+    pub fn get(&self, pointer: &str) -> Option<RewrittenShape> {
+        self.0.get(pointer).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &RewrittenShape)> {
+        self.0.iter()
+    }
+
+    /// Converts a value following `schema` back to the shape of the Rust type.
+    pub fn adapt(&self, schema: &Value, value: Value) -> Value {
+        if self.is_empty() {
+            return value;
+        }
+        self.adapt_at(schema, String::new(), value, 0)
+    }
+
+    fn adapt_at(&self, root: &Value, pointer: String, value: Value, depth: usize) -> Value {
+        // Stops on schemas referencing themselves without consuming the value.
+        if depth > 64 {
+            return value;
+        }
+        let Some(Value::Object(schema)) = root.pointer(&pointer) else {
+            return value;
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return match reference.strip_prefix('#') {
+                Some(reference) => self.adapt_at(root, reference.to_string(), value, depth + 1),
+                None => value,
+            };
+        }
+
+        let value = match (self.get(&pointer), value) {
+            (Some(RewrittenShape::MapEntries), Value::Array(entries)) => {
+                let value_pointer = format!("{pointer}/items/properties/value");
+                let mut map = Map::new();
+                for entry in entries {
+                    let Value::Object(mut entry) = entry else {
+                        continue;
+                    };
+                    let key = match entry.remove("key") {
+                        Some(Value::String(key)) => key,
+                        Some(key) => key.to_string(),
+                        None => continue,
+                    };
+                    let value = entry.remove("value").unwrap_or_default();
+                    let value = self.adapt_at(root, value_pointer.clone(), value, 0);
+                    map.insert(key, value);
+                }
+                return Value::Object(map);
+            }
+            (Some(RewrittenShape::Tuple), Value::Object(mut object)) => {
+                let items = (0..object.len())
+                    .map_while(|index| {
+                        let item = object.remove(&index.to_string())?;
+                        let item_pointer = format!("{pointer}/properties/{index}");
+                        Some(self.adapt_at(root, item_pointer, item, 0))
+                    })
+                    .collect();
+                return Value::Array(items);
+            }
+            (_, Value::Object(object)) => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                let object = object
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = if properties.is_some_and(|p| p.contains_key(&name)) {
+                            let name = name.replace('~', "~0").replace('/', "~1");
+                            let pointer = format!("{pointer}/properties/{name}");
+                            self.adapt_at(root, pointer, value, 0)
+                        } else if schema
+                            .get("additionalProperties")
+                            .is_some_and(Value::is_object)
+                        {
+                            let pointer = format!("{pointer}/additionalProperties");
+                            self.adapt_at(root, pointer, value, 0)
+                        } else {
+                            value
+                        };
+                        (name, value)
+                    })
+                    .collect();
+                Value::Object(object)
+            }
+            (_, Value::Array(array)) => {
+                let items = array
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, item)| match schema.get("items") {
+                        Some(Value::Array(_)) => {
+                            let pointer = format!("{pointer}/items/{index}");
+                            self.adapt_at(root, pointer, item, 0)
+                        }
+                        Some(Value::Object(_)) => {
+                            self.adapt_at(root, format!("{pointer}/items"), item, 0)
+                        }
+                        _ => item,
+                    })
+                    .collect();
+                Value::Array(items)
+            }
+            (_, value) => value,
+        };
+
+        let mut value = value;
+        if let Some(Value::Array(variants)) = schema.get("allOf") {
+            for index in 0..variants.len() {
+                let pointer = format!("{pointer}/allOf/{index}");
+                value = self.adapt_at(root, pointer, value, depth + 1);
+            }
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(variants)) = schema.get(key) {
+                // The output follows the first variant it is valid for.
+                let variant = variants
+                    .iter()
+                    .position(|variant| super::validator::is_valid(root, variant, &value));
+                if let Some(index) = variant {
+                    let pointer = format!("{pointer}/{key}/{index}");
+                    value = self.adapt_at(root, pointer, value, depth + 1);
+                }
+            }
+        }
+        value
+    }
+}
+
+/// Rewrites the maps, tuples and flattened enums of a schema into
+/// strict-compatible shapes, marking the ones outputs need converting back from.
+fn rewrite_shapes_of(schema: &mut Schema) {
+    let mut value = serde_json::to_value(&*schema).expect("unreachable");
+    rewrite_shapes(&mut value);
+    *schema = serde_json::from_value(value).expect("unreachable");
+}
+
+fn rewrite_shapes(schema: &mut Value) {
+    let Value::Object(object) = schema else {
+        return;
+    };
+    for (_, schema) in subschemas_mut(object, "") {
+        rewrite_shapes(schema);
+    }
+
+    let has_type = |object: &Map<String, Value>, r#type: &str| match object.get("type") {
+        Some(Value::String(t)) => t == r#type,
+        Some(Value::Array(types)) => types.iter().any(|t| t == r#type),
+        _ => false,
+    };
+    let has_properties = object
+        .get("properties")
+        .and_then(Value::as_object)
+        .is_some_and(|properties| !properties.is_empty());
+
+    if has_type(object, "object") && !has_properties {
+        // A map: `{"type": "object", "additionalProperties": S}`.
+        let Some(value) = object
+            .remove("additionalProperties")
+            .filter(|value| *value != Value::Bool(false))
+        else {
+            return;
+        };
+        object.remove("properties");
+        object.remove("required");
+        replace_type(object, "object", "array");
+        object.insert(
+            "items".to_string(),
+            serde_json::json!({
+                "type": "object",
+                "properties": { "key": { "type": "string" }, "value": value },
+                "required": ["key", "value"]
+            }),
+        );
+        object.insert(REWRITTEN_SHAPE.to_string(), "map".into());
+    } else if let Some(Value::Array(items)) = object.get_mut("items") {
+        // A tuple: `{"type": "array", "items": [S0, S1, ...]}`.
+        let items = take(items);
+        let required: Vec<Value> = (0..items.len()).map(|i| i.to_string().into()).collect();
+        let properties: Map<String, Value> = (0..items.len())
+            .map(|index| index.to_string())
+            .zip(items)
+            .collect();
+        for keyword in ["items", "minItems", "maxItems"] {
+            object.remove(keyword);
+        }
+        replace_type(object, "array", "object");
+        object.insert("properties".to_string(), properties.into());
+        object.insert("required".to_string(), required.into());
+        object.insert(REWRITTEN_SHAPE.to_string(), "tuple".into());
+    } else if has_properties {
+        // An enum flattened into a struct: the fields of the struct next to the
+        // variants. Each variant gets the fields instead.
+        let Some(key) = ["oneOf", "anyOf"]
+            .into_iter()
+            .find(|key| object.contains_key(*key))
+        else {
+            return;
+        };
+        let Some(Value::Array(variants)) = object.get_mut(key) else {
+            return;
+        };
+        let all_objects = variants
+            .iter()
+            .all(|variant| variant.get("properties").is_some_and(Value::is_object));
+        if !all_objects {
+            return;
+        }
+        let mut variants = take(variants);
+        let properties = object.remove("properties").unwrap_or_default();
+        let required = object.remove("required").unwrap_or_default();
+        for variant in variants.iter_mut() {
+            let Value::Object(variant) = variant else {
+                continue;
+            };
+            variant.insert("type".to_string(), "object".into());
+            if let (Some(Value::Object(variant_properties)), Value::Object(properties)) =
+                (variant.get_mut("properties"), &properties)
+            {
+                for (name, property) in properties {
+                    variant_properties
+                        .entry(name.clone())
+                        .or_insert_with(|| property.clone());
+                }
+            }
+            if let Value::Array(required) = &required {
+                let variant_required = variant
+                    .entry("required")
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(variant_required) = variant_required {
+                    variant_required.extend(required.iter().cloned());
+                }
+            }
+        }
+        for keyword in ["type", "additionalProperties"] {
+            object.remove(keyword);
+        }
+        object.insert(key.to_string(), variants.into());
+    }
+}
+
+/// Replaces `from` with `to` in the `type` of a schema, keeping `null`.
+fn replace_type(object: &mut Map<String, Value>, from: &str, to: &str) {
+    match object.get_mut("type") {
+        Some(Value::Array(types)) => {
+            for r#type in types.iter_mut().filter(|t| *t == from) {
+                *r#type = to.into();
+            }
+        }
+        Some(r#type) => *r#type = to.into(),
+        None => {}
+    }
+}
+
+/// Removes the markers of rewritten shapes, recording their final pointers.
+fn collect_shape_markers(schema: &mut Value, pointer: String, adapter: &mut ResponseAdapter) {
+    let Value::Object(object) = schema else {
+        return;
+    };
+    match object
+        .remove(REWRITTEN_SHAPE)
+        .as_ref()
+        .and_then(Value::as_str)
+    {
+        Some("map") => {
+            adapter
+                .0
+                .insert(pointer.clone(), RewrittenShape::MapEntries);
+        }
+        Some("tuple") => {
+            adapter.0.insert(pointer.clone(), RewrittenShape::Tuple);
+        }
+        _ => {}
+    }
+    for (pointer, schema) in subschemas_mut(object, &pointer) {
+        collect_shape_markers(schema, pointer, adapter);
+    }
+}
+
+/// Describes constraints, e.g. "must be between 1 and 10, format: email". The
+/// formats of numbers, like `uint8`, and the bounds they imply, like the minimum of
+/// 0 of unsigned integers, go without saying and are not described.
+fn describe_constraints(constraints: &Map<String, Value>) -> Option<String> {
+    let get = |keyword: &str| constraints.get(keyword).map(display_number);
+    let format = constraints.get("format").and_then(Value::as_str);
+    let (implied_min, implied_max) = format.map_or((None, None), implied_bounds);
+    let bound = |keyword: &str, implied: Option<f64>| {
+        let value = constraints.get(keyword)?;
+        match (value.as_f64(), implied) {
+            (Some(value), Some(implied)) if value == implied => None,
+            _ => Some(display_number(value)),
+        }
+    };
+    let mut parts = Vec::new();
+    match (bound("minimum", implied_min), bound("maximum", implied_max)) {
+        (Some(min), Some(max)) => parts.push(format!("must be between {min} and {max}")),
+        (Some(min), None) => parts.push(format!("must be at least {min}")),
+        (None, Some(max)) => parts.push(format!("must be at most {max}")),
+        (None, None) => {}
+    }
+    if let Some(min) = get("exclusiveMinimum") {
+        parts.push(format!("must be greater than {min}"));
+    }
+    if let Some(max) = get("exclusiveMaximum") {
+        parts.push(format!("must be less than {max}"));
+    }
+    if let Some(multiple) = get("multipleOf") {
+        parts.push(format!("must be a multiple of {multiple}"));
+    }
+    match (get("minLength"), get("maxLength")) {
+        (Some(min), Some(max)) => parts.push(format!("must be {min} to {max} characters long")),
+        (Some(min), None) => parts.push(format!("must be at least {min} characters long")),
+        (None, Some(max)) => parts.push(format!("must be at most {max} characters long")),
+        (None, None) => {}
+    }
+    if let Some(Value::String(pattern)) = constraints.get("pattern") {
+        parts.push(format!("must match the pattern `{pattern}`"));
+    }
+    if let Some(Value::String(format)) = constraints.get("format") {
+        let numeric = ["int", "uint", "float", "double"]
+            .iter()
+            .any(|prefix| format.starts_with(prefix));
+        if !numeric {
+            parts.push(format!("format: {format}"));
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// The range of the integer formats schemars generates, like `uint8`.
+fn implied_bounds(format: &str) -> (Option<f64>, Option<f64>) {
+    let bits = |prefix: &str| format.strip_prefix(prefix)?.parse::<i32>().ok();
+    if let Some(bits) = bits("uint").filter(|bits| *bits < 64) {
+        return (Some(0.0), Some(2f64.powi(bits) - 1.0));
+    }
+    if format.starts_with("uint") {
+        return (Some(0.0), None);
+    }
+    match bits("int").filter(|bits| *bits < 64) {
+        Some(bits) => (
+            Some(-(2f64.powi(bits - 1))),
+            Some(2f64.powi(bits - 1) - 1.0),
+        ),
+        None => (None, None),
+    }
+}
+
+/// Displays whole numbers, which schemars generates as floats, without decimals.
+fn display_number(value: &Value) -> String {
+    match value.as_f64() {
+        Some(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+            format!("{}", number as i64)
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Replaces `oneOf` with `anyOf`, keeping the variants of an existing `anyOf`, like
+/// the `null` variant of an optional reference.
+fn one_of_to_any_of(subschemas: &mut SubschemaValidation) {
+    if let Some(one_of) = subschemas.one_of.take() {
+        subschemas
+            .any_of
+            .get_or_insert_with(Vec::new)
+            .extend(one_of);
+    }
+}
+
+/// Applies the [`SchemaTransform`] of a style to every schema object.
 pub struct SchemaPostProcessor {
     pub style: JsonSchemaStyle,
 }
 
 impl Visitor for SchemaPostProcessor {
     fn visit_schema_object(&mut self, schema: &mut SchemaObject) {
-        if let Some(sub) = &mut schema.subschemas {
-            sub.any_of = take(&mut sub.one_of);
+        self.style.transform_object(schema);
+        visit_schema_object(self, schema);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::schema_lint::lint_strict_schema;
+    use crate::tests::{json_response, serve_responses};
+    use serde_json::json;
+
+    #[derive(JsonSchema, Deserialize, Debug, PartialEq)]
+    struct Answer {
+        value: u32,
+    }
+
+    fn completion_json(content: &str) -> String {
+        json!({
+            "id": "c",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "m",
+            "choices": [
+                { "index": 0, "finish_reason": "stop", "message": { "role": "assistant", "content": content } }
+            ]
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn create_validated() {
+        let credentials = serve_responses(vec![
+            json_response(&completion_json("{\"value\":\"3\"}")),
+            json_response(&completion_json("{\"value\":3")),
+            json_response(&completion_json("{\"value\":3}")),
+            json_response(&completion_json("{}")),
+        ])
+        .await;
+        let validated = ChatCompletion::builder("m", [])
+            .credentials(credentials.clone())
+            .create_validated::<Answer>(JsonSchemaStyle::Grok, 2)
+            .await
+            .unwrap();
+        assert_eq!(validated.value, Answer { value: 3 });
+        let failures: Vec<String> = validated
+            .failed_attempts
+            .iter()
+            .map(|attempt| attempt.failure.to_string())
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                "Schema violations: /value: expected integer, got string",
+                "Failed to deserialize the output: EOF while parsing an object at line 1 column 10",
+            ]
+        );
+        assert_eq!(
+            validated.failed_attempts[0].failure.corrective_message(),
+            "Your response is not valid:\n- /value: expected integer, got string\nRespond again with only the corrected JSON."
+        );
+
+        let error = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_validated::<Answer>(JsonSchemaStyle::Grok, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid output after 1 attempts, the last one failed with: Schema violations: (root): missing required property `value`"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_validated_with_response_format() {
+        #[derive(JsonSchema, Deserialize, Debug, PartialEq)]
+        struct Tally {
+            counts: BTreeMap<String, u32>,
+            range: (u32, u32),
         }
-        schema.format = None;
-        if let Some(sub) = &mut schema.object {
-            if self.style == JsonSchemaStyle::OpenAI {
-                if sub.additional_properties.is_none() {
-                    sub.additional_properties = Some(Box::new(Schema::Bool(false)));
-                }
-                sub.required = sub.properties.keys().map(|s| s.clone()).collect();
+
+        // The output follows the plain schema, the model never saw the rewritten one.
+        let credentials = serve_responses(vec![json_response(&completion_json(
+            "{\"counts\":{\"a\":1},\"range\":[0,2]}",
+        ))])
+        .await;
+        let validated = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .response_format(ChatCompletionResponseFormat::json_object())
+            .create_validated::<Tally>(JsonSchemaStyle::OpenAI, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            validated.value,
+            Tally {
+                counts: [("a".to_string(), 1)].into(),
+                range: (0, 2),
             }
+        );
+    }
+
+    /// A dated event
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Event {
+        #[schemars(length(max = 20))]
+        name: String,
+        #[schemars(range(min = 1, max = 5))]
+        priority: u8,
+        date: Option<Date>,
+        kind: Kind,
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Date {
+        #[schemars(regex(pattern = r"^\d{4}-\d{2}-\d{2}$"))]
+        day: String,
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    #[serde(tag = "type")]
+    enum Kind {
+        Meeting,
+        Call { number: String },
+    }
+
+    #[test]
+    fn provider_styles() {
+        let (openai, description) = generate_json_schema::<Event>(JsonSchemaStyle::OpenAI);
+        assert_eq!(description.as_deref(), Some("A dated event"));
+        assert_eq!(openai["additionalProperties"], json!(false));
+        assert_eq!(
+            openai["required"],
+            json!(["date", "kind", "name", "priority"])
+        );
+        assert_eq!(
+            openai["properties"]["date"]["type"],
+            json!(["object", "null"])
+        );
+        assert!(openai["properties"]["priority"].get("maximum").is_none());
+
+        let (gemini, _) = generate_json_schema::<Event>(JsonSchemaStyle::Gemini);
+        let date = &gemini["properties"]["date"];
+        assert_eq!(date["type"], json!("object"));
+        assert_eq!(date["nullable"], json!(true));
+        assert!(gemini.get("additionalProperties").is_none());
+        assert_eq!(gemini["required"], json!(["kind", "name", "priority"]));
+        assert_eq!(gemini["properties"]["priority"]["maximum"], json!(5.0));
+        assert!(gemini["properties"]["priority"].get("format").is_none());
+        let kinds = gemini["properties"]["kind"]["anyOf"].as_array().unwrap();
+        assert_eq!(kinds[0]["properties"]["type"]["enum"], json!(["Meeting"]));
+        assert!(!gemini.to_string().contains("const"));
+
+        let (anthropic, _) = generate_json_schema::<Event>(JsonSchemaStyle::Anthropic);
+        assert_eq!(anthropic["properties"]["name"]["maxLength"], json!(20));
+        assert_eq!(
+            anthropic["properties"]["priority"]["format"],
+            json!("uint8")
+        );
+        assert_eq!(
+            anthropic["properties"]["date"]["properties"]["day"]["pattern"],
+            json!(r"^\d{4}-\d{2}-\d{2}$")
+        );
+        assert!(anthropic["properties"]["kind"].get("oneOf").is_some());
+
+        let (ollama, _) = generate_json_schema::<Event>(JsonSchemaStyle::Ollama);
+        assert!(ollama["properties"]["priority"].get("format").is_none());
+        assert_eq!(ollama["properties"]["priority"]["minimum"], json!(1.0));
+        let (vllm, _) = generate_json_schema::<Event>(JsonSchemaStyle::Vllm);
+        assert_eq!(vllm, anthropic);
+    }
+
+    #[test]
+    fn stripped_constraints() {
+        let (schema, _, side_table) = generate_json_schema_with_options::<Event>(
+            JsonSchemaStyle::OpenAI,
+            SchemaOptions::default(),
+        );
+        let properties = &schema["properties"];
+        assert_eq!(
+            properties["name"]["description"],
+            json!("Must be at most 20 characters long")
+        );
+        assert_eq!(
+            properties["priority"]["description"],
+            json!("Must be between 1 and 5")
+        );
+        assert!(properties["priority"].get("maximum").is_none());
+        assert_eq!(
+            properties["date"]["properties"]["day"]["description"],
+            json!(r"Must match the pattern `^\d{4}-\d{2}-\d{2}$`")
+        );
+        assert_eq!(
+            side_table.constraints.get("/properties/priority").unwrap()["format"],
+            json!("uint8")
+        );
+        assert_eq!(side_table.constraints.iter().count(), 3);
+        assert!(schema.to_string().find("maxLength").is_none());
+
+        let event = json!({
+            "name": "Standup",
+            "priority": 9,
+            "date": { "day": "2024-01-01" },
+            "kind": { "type": "Meeting" }
+        });
+        assert_eq!(
+            side_table.constraints.check(&schema, &event),
+            [SchemaViolation {
+                pointer: "/priority".to_string(),
+                message: "9 is greater than the maximum of 5".to_string()
+            }]
+        );
+        let event = json!({
+            "name": "Standup",
+            "priority": 1,
+            "date": { "day": "tomorrow" },
+            "kind": { "type": "Meeting" }
+        });
+        assert_eq!(
+            side_table.constraints.check(&schema, &event),
+            [SchemaViolation {
+                pointer: "/date/day".to_string(),
+                message: r"does not match the pattern `^\d{4}-\d{2}-\d{2}$`".to_string()
+            }]
+        );
+
+        // The bounds of integer formats go without saying.
+        let describe = |constraints: Value| describe_constraints(constraints.as_object().unwrap());
+        assert_eq!(
+            describe(json!({ "format": "uint32", "minimum": 0.0 })),
+            None
+        );
+        assert_eq!(
+            describe(json!({ "format": "int8", "minimum": -128.0, "maximum": 127.0 })),
+            None
+        );
+        assert_eq!(
+            describe(json!({ "format": "uint8", "minimum": 0.0, "maximum": 10.0 })).as_deref(),
+            Some("must be at most 10")
+        );
+        assert_eq!(
+            describe(json!({ "format": "uint64", "minimum": 1.0 })).as_deref(),
+            Some("must be at least 1")
+        );
+
+        let (anthropic, _, side_table) = generate_json_schema_with_options::<Event>(
+            JsonSchemaStyle::Anthropic,
+            SchemaOptions::default(),
+        );
+        assert!(side_table.constraints.is_empty());
+        assert!(anthropic["properties"]["priority"]
+            .get("description")
+            .is_none());
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Wrapper<T> {
+        inner: T,
+    }
+
+    #[test]
+    fn tool_definitions() {
+        let function =
+            ToolCallFunctionDefinition::new::<Event>(Some(true), JsonSchemaStyle::OpenAI);
+        assert_eq!(function.name, "Event");
+        assert_eq!(function.description.as_deref(), Some("A dated event"));
+        let parameters = function.parameters.as_ref().unwrap();
+        assert!(parameters.get("$schema").is_none());
+        assert_eq!(parameters["additionalProperties"], json!(false));
+        assert!(parameters["properties"]["kind"].get("anyOf").is_some());
+        assert_eq!(function.lint(), []);
+
+        let function = ToolCallFunctionDefinition::new::<Event>(None, JsonSchemaStyle::Grok);
+        let parameters = function.parameters.unwrap();
+        assert_eq!(parameters["required"], json!(["kind", "name", "priority"]));
+
+        let function =
+            ToolCallFunctionDefinition::new::<Wrapper<Vec<Event>>>(None, JsonSchemaStyle::OpenAI);
+        assert_eq!(function.name, "Wrapper_for_Array_of_Event");
+        assert_eq!(sanitize_name("get weather.v2"), "get_weather_v2");
+        assert_eq!(sanitize_name(&"a".repeat(70)).len(), 64);
+        assert_eq!(sanitize_name(""), "_");
+    }
+
+    /// A comment thread
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Comment {
+        #[schemars(length(max = 280))]
+        text: String,
+        replies: Vec<Comment>,
+        parent: Option<Box<Comment>>,
+    }
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)]
+    struct Route {
+        from: Date,
+        to: Date,
+    }
+
+    #[test]
+    fn definitions() {
+        let (format, side_table) = ChatCompletionResponseFormatJsonSchema::with_side_table::<Comment>(
+            true,
+            JsonSchemaStyle::OpenAI,
+        );
+        assert_eq!(format.lint(), []);
+        let schema = format.schema.unwrap();
+        assert_eq!(
+            schema["properties"]["replies"]["items"],
+            json!({ "$ref": "#/$defs/Comment" })
+        );
+        assert_eq!(
+            schema["properties"]["parent"]["anyOf"],
+            json!([{ "$ref": "#/$defs/Comment" }, { "type": "null" }])
+        );
+        let definition = &schema["$defs"]["Comment"];
+        assert_eq!(definition["additionalProperties"], json!(false));
+        assert_eq!(definition["required"], json!(["parent", "replies", "text"]));
+        assert!(side_table
+            .constraints
+            .get("/$defs/Comment/properties/text")
+            .is_some());
+
+        let comment = json!({
+            "text": "First",
+            "replies": [{ "text": "Second", "replies": [], "parent": null }],
+            "parent": { "text": "Zeroth", "replies": [], "parent": 1 }
+        });
+        let violations = side_table.constraints.check(&schema, &comment);
+        assert_eq!(violations[0].pointer, "/parent");
+
+        let (inline, _) = generate_json_schema::<Route>(JsonSchemaStyle::OpenAI);
+        assert!(inline.get("$defs").is_none());
+        assert_eq!(inline["properties"]["from"]["type"], json!("object"));
+        let (schema, _, _) = generate_json_schema_with_options::<Route>(
+            JsonSchemaStyle::OpenAI,
+            SchemaOptions { definitions: true },
+        );
+        assert_eq!(
+            schema["properties"]["from"],
+            json!({ "$ref": "#/$defs/Date" })
+        );
+        assert_eq!(
+            schema["properties"]["to"],
+            json!({ "$ref": "#/$defs/Date" })
+        );
+        assert_eq!(
+            schema["$defs"]["Date"]["additionalProperties"],
+            json!(false)
+        );
+        assert_eq!(lint_strict_schema(&schema, ""), []);
+    }
+
+    #[derive(JsonSchema, Deserialize, Debug, PartialEq)]
+    #[serde(tag = "kind")]
+    enum Shape {
+        Circle { radius: f64 },
+        Square { side: f64 },
+    }
+
+    #[derive(JsonSchema, Deserialize, Debug, PartialEq)]
+    struct Part {
+        name: String,
+        #[serde(flatten)]
+        shape: Shape,
+    }
+
+    #[derive(JsonSchema, Deserialize, Debug, PartialEq)]
+    struct Inventory {
+        counts: std::collections::HashMap<String, u32>,
+        bins: BTreeMap<u32, Vec<(String, bool)>>,
+        labels: Option<BTreeMap<String, String>>,
+        position: (i32, i32),
+        part: Part,
+    }
+
+    #[test]
+    fn rewritten_shapes() {
+        let (format, side_table) = ChatCompletionResponseFormatJsonSchema::with_side_table::<
+            Inventory,
+        >(true, JsonSchemaStyle::OpenAI);
+        assert_eq!(format.lint(), []);
+        assert_eq!(crate::chat::schema_lint::lint_type::<Inventory>(), []);
+        let schema = format.schema.unwrap();
+        assert_eq!(
+            schema["properties"]["labels"]["type"],
+            json!(["array", "null"])
+        );
+        assert_eq!(
+            side_table.adapter.iter().collect::<Vec<_>>(),
+            [
+                (&"/properties/bins".to_string(), &RewrittenShape::MapEntries),
+                (
+                    &"/properties/bins/items/properties/value/items".to_string(),
+                    &RewrittenShape::Tuple
+                ),
+                (
+                    &"/properties/counts".to_string(),
+                    &RewrittenShape::MapEntries
+                ),
+                (
+                    &"/properties/labels".to_string(),
+                    &RewrittenShape::MapEntries
+                ),
+                (&"/properties/position".to_string(), &RewrittenShape::Tuple),
+            ]
+        );
+
+        let output = json!({
+            "counts": [{ "key": "bolts", "value": 12 }],
+            "bins": [{ "key": "7", "value": [{ "0": "a", "1": true }] }],
+            "labels": null,
+            "position": { "0": -1, "1": 2 },
+            "part": { "kind": "Square", "name": "plate", "side": 2.5 }
+        });
+        assert!(super::super::validator::validate(&schema, &output).is_empty());
+        let inventory: Inventory = side_table.deserialize(&schema, output).unwrap();
+        assert_eq!(
+            inventory,
+            Inventory {
+                counts: [("bolts".to_string(), 12)].into(),
+                bins: [(7, vec![("a".to_string(), true)])].into(),
+                labels: None,
+                position: (-1, 2),
+                part: Part {
+                    name: "plate".to_string(),
+                    shape: Shape::Square { side: 2.5 },
+                },
+            }
+        );
+
+        #[derive(JsonSchema)]
+        #[allow(dead_code)]
+        struct Untyped {
+            extra: std::collections::HashMap<String, Value>,
         }
-        if let Some(num) = &mut schema.number {
-            num.multiple_of = None;
-            num.exclusive_maximum = None;
-            num.exclusive_minimum = None;
-            num.maximum = None;
-            num.minimum = None;
-        }
-        if let Some(str) = &mut schema.string {
-            str.max_length = None;
-            str.min_length = None;
-            str.pattern = None;
+        let diagnostics: Vec<String> = crate::chat::schema_lint::lint_type::<Untyped>()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            diagnostics,
+            ["/schema/properties/extra/items/properties/value: arbitrary values, like `serde_json::Value`, are not supported, use a typed value"]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrapped_roots() {
+        let (format, side_table) = ChatCompletionResponseFormatJsonSchema::with_side_table::<
+            Vec<Answer>,
+        >(true, JsonSchemaStyle::OpenAI);
+        assert_eq!(format.lint(), []);
+        assert!(side_table.wrapped);
+        let schema = format.schema.unwrap();
+        assert_eq!(schema["required"], json!(["value"]));
+        assert_eq!(schema["properties"]["value"]["type"], json!("array"));
+        assert!(side_table
+            .constraints
+            .get("/properties/value/items/properties/value")
+            .is_some());
+        assert_eq!(crate::chat::schema_lint::lint_type::<Shape>(), []);
+        assert_eq!(
+            crate::chat::schema_lint::lint_type::<BTreeMap<String, u32>>(),
+            []
+        );
+        assert!(
+            !ChatCompletionResponseFormatJsonSchema::with_side_table::<Answer>(
+                true,
+                JsonSchemaStyle::OpenAI
+            )
+            .1
+            .wrapped
+        );
+
+        let credentials = serve_responses(vec![
+            json_response(&completion_json(
+                "{\"value\":[{\"value\":1},{\"value\":2}]}",
+            )),
+            json_response(&completion_json(
+                "{\"value\":{\"kind\":\"Circle\",\"radius\":1.0}}",
+            )),
+        ])
+        .await;
+        let parsed = ChatCompletion::builder("m", [])
+            .credentials(credentials.clone())
+            .create_parsed::<Vec<Answer>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            parsed.choices[0].as_ref().unwrap(),
+            &ParsedOutput::Parsed(vec![Answer { value: 1 }, Answer { value: 2 }])
+        );
+        let validated = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_validated::<Shape>(JsonSchemaStyle::Gemini, 0)
+            .await
+            .unwrap();
+        assert_eq!(validated.value, Shape::Circle { radius: 1.0 });
+
+        let choice: ChatCompletionChoice = serde_json::from_value(json!({
+            "index": 0,
+            "finish_reason": "stop",
+            "message": { "role": "assistant", "content": "{\"value\":[3]}" }
+        }))
+        .unwrap();
+        assert_eq!(
+            ParsedOutput::<Vec<u32>>::from_choice(&choice).unwrap(),
+            ParsedOutput::Parsed(vec![3])
+        );
+    }
+
+    #[test]
+    fn custom_style() {
+        struct Renamed;
+        impl SchemaTransform for Renamed {
+            fn name(&self) -> &str {
+                "renamed"
+            }
+            fn transform_object(&self, schema: &mut SchemaObject) {
+                JsonSchemaStyle::Gemini.transform_object(schema);
+            }
+            fn transform_value(&self, schema: &mut Value) {
+                schema["title"] = json!("renamed");
+            }
         }
-        visit_schema_object(self, schema);
+
+        let style = JsonSchemaStyle::Custom(&Renamed);
+        assert_eq!(style, JsonSchemaStyle::Custom(&Renamed));
+        assert_ne!(style, JsonSchemaStyle::Gemini);
+        assert_eq!(format!("{style:?}"), "Custom(\"renamed\")");
+        let (schema, _) = generate_json_schema::<Event>(style);
+        assert_eq!(schema["title"], json!("renamed"));
+        let date = &schema["properties"]["date"];
+        assert_eq!(date["type"], json!(["object", "null"]));
+        assert!(date.get("nullable").is_none());
+    }
+
+    #[tokio::test]
+    async fn create_parsed() {
+        let body = json!({
+            "id": "c",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "m",
+            "choices": [
+                { "index": 0, "finish_reason": "stop", "message": { "role": "assistant", "content": "{\"value\":3}" } },
+                { "index": 1, "finish_reason": "stop", "message": { "role": "assistant", "content": null, "refusal": "I can't help with that." } },
+                { "index": 2, "finish_reason": "length", "message": { "role": "assistant", "content": "{\"val" } },
+                { "index": 3, "finish_reason": "stop", "message": { "role": "assistant", "content": "{}" } }
+            ]
+        });
+        let credentials = serve_responses(vec![json_response(&body.to_string())]).await;
+        let parsed = ChatCompletion::builder("m", [])
+            .credentials(credentials)
+            .create_parsed::<Answer>()
+            .await
+            .unwrap();
+
+        let mut choices = parsed.choices.into_iter();
+        assert_eq!(
+            choices.next().unwrap().unwrap(),
+            ParsedOutput::Parsed(Answer { value: 3 })
+        );
+        assert_eq!(
+            choices.next().unwrap().unwrap(),
+            ParsedOutput::Refusal("I can't help with that.".to_string())
+        );
+        assert!(matches!(
+            choices.next().unwrap(),
+            Err(ParseError::Truncated { content }) if content == "{\"val"
+        ));
+        assert!(matches!(
+            choices.next().unwrap(),
+            Err(ParseError::Deserialize { .. })
+        ));
     }
 }
@@ -0,0 +1,690 @@
+//! Run the tools the model asks for until it answers without calling any.
+//!
+//! Tools are registered as typed async handlers. Their arguments are described by a
+//! [`JsonSchema`] type, which is also used to parse the arguments the model sends.
+//!
+//! ```no_run
+//! use openai::chat::tools::{ToolRegistry, ToolRunner};
+//! use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+//! use schemars::JsonSchema;
+//! use serde::Deserialize;
+//!
+//! /// Get the current weather in a given location.
+//! #[derive(JsonSchema, Deserialize)]
+//! struct GetWeather {
+//!     location: String,
+//! }
+//!
+//! # async fn run() -> Result<(), openai::chat::tools::ToolRunError> {
+//! let mut registry = ToolRegistry::new();
+//! registry.register(|args: GetWeather| async move {
+//!     Ok(format!("It is sunny in {}", args.location))
+//! });
+//! let builder = ChatCompletion::builder(
+//!     "gpt-4o-mini",
+//!     [ChatCompletionMessage {
+//!         role: ChatCompletionMessageRole::User,
+//!         content: Some("What is the weather in Boston?".into()),
+//!         ..Default::default()
+//!     }],
+//! );
+//! let output = ToolRunner::new(registry).run(builder).await?;
+//! println!("{:?}", output.completion.choices[0].message.content);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::dynamic_schema::DynamicSchema;
+use super::structured_output::{JsonSchemaStyle, ParseError, ToolCallFunctionDefinition};
+use super::{
+    ChatCompletion, ChatCompletionBuilder, ChatCompletionMessage, ChatCompletionMessageRole,
+    ChatCompletionTool, ToolCall,
+};
+use crate::OpenAiError;
+use futures_util::future::BoxFuture;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{FutureExt, StreamExt};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+type ToolHandler = Arc<dyn Fn(String) -> BoxFuture<'static, ToolCallOutcome> + Send + Sync>;
+type EventHook = Box<dyn FnMut(&ToolRunEvent) + Send>;
+
+struct RegisteredTool {
+    definition: ToolCallFunctionDefinition,
+    timeout: Option<Duration>,
+    handler: ToolHandler,
+}
+
+/// A set of tools the model may call, each with the handler that runs it.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<RegisteredTool>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a tool described by its argument type `T`.
+    ///
+    /// The tool is named after `T` and described by its doc comment, like
+    /// [`ChatCompletionTool::new`], with a non-strict schema in the OpenAI style.
+    /// The handler's output is serialized as JSON, except for strings which are sent
+    /// to the model as they are.
+    pub fn register<T, R, F, Fut>(&mut self, handler: F) -> &mut Self
+    where
+        T: JsonSchema + DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<R>> + Send + 'static,
+    {
+        let (definition, side_table) =
+            ToolCallFunctionDefinition::with_side_table::<T>(None, JsonSchemaStyle::OpenAI);
+        let schema = definition.parameters.clone().unwrap_or_default();
+        let parse = move |arguments: &str| {
+            if !side_table.changes_shape() {
+                return serde_json::from_str(arguments).map_err(|error| error.to_string());
+            }
+            serde_json::from_str(arguments)
+                .and_then(|arguments| side_table.deserialize(&schema, arguments))
+                .map_err(|error| error.to_string())
+        };
+        self.insert(definition, parse, handler)
+    }
+
+    /// Registers a tool with an explicit definition, e.g. to set its name,
+    /// description or strictness. The arguments are still parsed as `T`.
+    pub fn register_with_definition<T, R, F, Fut>(
+        &mut self,
+        definition: ToolCallFunctionDefinition,
+        handler: F,
+    ) -> &mut Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<R>> + Send + 'static,
+    {
+        let parse = |arguments: &str| serde_json::from_str(arguments).map_err(|e| e.to_string());
+        self.insert(definition, parse, handler)
+    }
+
+    /// Registers a tool whose parameters are defined at runtime, with a non-strict
+    /// schema. The arguments are validated against the schema before being passed
+    /// to the handler.
+    pub fn register_dynamic<R, F, Fut>(
+        &mut self,
+        name: &str,
+        parameters: DynamicSchema,
+        handler: F,
+    ) -> &mut Self
+    where
+        R: Serialize + Send + 'static,
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<R>> + Send + 'static,
+    {
+        let definition = ToolCallFunctionDefinition::dynamic(name, &parameters, None);
+        let parse = move |arguments: &str| match parameters.parse(arguments) {
+            Ok(arguments) => Ok(arguments),
+            Err(ParseError::Schema { violations, .. }) => {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                Err(violations.join(", "))
+            }
+            Err(error) => Err(error.to_string()),
+        };
+        self.insert(definition, parse, handler)
+    }
+
+    /// Registers a tool whose arguments are parsed by `parse`, returning the error
+    /// sent back to the model.
+    fn insert<T, P, R, F, Fut>(
+        &mut self,
+        definition: ToolCallFunctionDefinition,
+        parse: P,
+        handler: F,
+    ) -> &mut Self
+    where
+        T: Send + 'static,
+        P: Fn(&str) -> Result<T, String> + Send + Sync + 'static,
+        R: Serialize + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<R>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let handler: ToolHandler = Arc::new(move |arguments: String| {
+            let arguments = match parse(&arguments) {
+                Ok(arguments) => arguments,
+                Err(error) => {
+                    return async move { ToolCallOutcome::InvalidArguments(error) }.boxed()
+                }
+            };
+            let result = handler(arguments);
+            async move {
+                match result.await {
+                    Ok(output) => match serde_json::to_value(output) {
+                        Ok(Value::String(output)) => ToolCallOutcome::Success(output),
+                        Ok(output) => ToolCallOutcome::Success(output.to_string()),
+                        Err(error) => ToolCallOutcome::Failed(error.to_string()),
+                    },
+                    Err(error) => ToolCallOutcome::Failed(format!("{error:#}")),
+                }
+            }
+            .boxed()
+        });
+        self.tools
+            .retain(|tool| tool.definition.name != definition.name);
+        self.tools.push(RegisteredTool {
+            definition,
+            timeout: None,
+            handler,
+        });
+        self
+    }
+
+    /// Sets how long the named tool may run, overriding [`ToolRunner::timeout`].
+    /// Fails if no tool with this name is registered.
+    pub fn set_timeout(
+        &mut self,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<&mut Self, UnknownToolError> {
+        match self.tools.iter_mut().find(|t| t.definition.name == name) {
+            Some(tool) => tool.timeout = Some(timeout),
+            None => {
+                return Err(UnknownToolError {
+                    name: name.to_string(),
+                })
+            }
+        }
+        Ok(self)
+    }
+
+    /// The definitions of all registered tools, as sent to the model.
+    pub fn definitions(&self) -> Vec<ChatCompletionTool> {
+        self.tools
+            .iter()
+            .map(|tool| ChatCompletionTool::Function {
+                function: tool.definition.clone(),
+            })
+            .collect()
+    }
+
+    fn get(&self, name: &str) -> Option<&RegisteredTool> {
+        self.tools.iter().find(|tool| tool.definition.name == name)
+    }
+}
+
+/// How a single tool call ended. Every outcome is reported back to the model.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ToolCallOutcome {
+    /// The tool ran, with its serialized output.
+    Success(String),
+    /// The arguments sent by the model did not match the tool's argument type.
+    InvalidArguments(String),
+    /// The tool returned an error.
+    Failed(String),
+    /// The tool did not finish in time.
+    TimedOut(Duration),
+    /// The model called a tool that is not registered.
+    UnknownTool,
+}
+
+impl ToolCallOutcome {
+    /// The content of the `tool` message answering the call.
+    pub fn to_content(&self) -> String {
+        match self {
+            ToolCallOutcome::Success(output) => output.clone(),
+            ToolCallOutcome::InvalidArguments(error) => {
+                format!("Error: invalid arguments: {error}. Fix the arguments and try again.")
+            }
+            ToolCallOutcome::Failed(error) => format!("Error: {error}"),
+            ToolCallOutcome::TimedOut(timeout) => {
+                format!("Error: the tool did not finish within {timeout:?}")
+            }
+            ToolCallOutcome::UnknownTool => "Error: no tool with this name exists".to_string(),
+        }
+    }
+}
+
+/// A step of a [`ToolRunner`], passed to its event hook.
+#[derive(Debug)]
+pub enum ToolRunEvent<'a> {
+    /// The model responded.
+    Completion {
+        iteration: usize,
+        completion: &'a ChatCompletion,
+    },
+    /// A tool call requested by the model finished.
+    ToolCallFinished {
+        iteration: usize,
+        tool_call: &'a ToolCall,
+        outcome: &'a ToolCallOutcome,
+    },
+}
+
+/// The result of a successful [`ToolRunner::run`].
+#[derive(Debug, Clone)]
+pub struct ToolRunOutput {
+    /// The last completion, which did not call any tools.
+    pub completion: ChatCompletion,
+    /// The whole conversation, including the final assistant message.
+    pub messages: Vec<ChatCompletionMessage>,
+    /// How many completions were requested.
+    pub iterations: usize,
+}
+
+/// No tool with this name is registered.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnknownToolError {
+    pub name: String,
+}
+
+impl std::fmt::Display for UnknownToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No tool named `{}` is registered", self.name)
+    }
+}
+
+impl std::error::Error for UnknownToolError {}
+
+#[derive(Debug)]
+pub enum ToolRunError {
+    /// A request to the API failed.
+    Api(OpenAiError),
+    /// The model returned no choices.
+    NoChoices,
+    /// The request asks for more than one choice, but only one can be followed.
+    MultipleChoices(u8),
+    /// The model was still calling tools after the maximum number of iterations.
+    MaxIterations {
+        /// The conversation so far, ending with the answers to the last tool calls.
+        messages: Vec<ChatCompletionMessage>,
+    },
+}
+
+impl std::fmt::Display for ToolRunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolRunError::Api(error) => write!(f, "API error: {error}"),
+            ToolRunError::NoChoices => f.write_str("The completion has no choices"),
+            ToolRunError::MultipleChoices(n) => write!(
+                f,
+                "Tools can only be run for a single choice, but {n} were requested"
+            ),
+            ToolRunError::MaxIterations { .. } => {
+                f.write_str("The model was still calling tools after the maximum iterations")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ToolRunError {}
+
+impl From<OpenAiError> for ToolRunError {
+    fn from(value: OpenAiError) -> Self {
+        ToolRunError::Api(value)
+    }
+}
+
+/// Drives a chat completion, running the tools the model calls and sending their
+/// results back, until the model answers without calling tools.
+///
+/// Only a single choice can be followed, so requests with `n` above 1 are rejected.
+pub struct ToolRunner {
+    registry: ToolRegistry,
+    max_iterations: usize,
+    parallel: bool,
+    timeout: Option<Duration>,
+    on_event: Option<EventHook>,
+}
+
+impl ToolRunner {
+    pub fn new(registry: ToolRegistry) -> Self {
+        ToolRunner {
+            registry,
+            max_iterations: 10,
+            parallel: true,
+            timeout: None,
+            on_event: None,
+        }
+    }
+
+    /// The maximum number of completions to request. Defaults to 10.
+    pub fn max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Whether the tool calls of one completion run concurrently. Defaults to true.
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    /// How long any tool may run, unless set per tool with [`ToolRegistry::set_timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Calls `on_event` for every completion and every finished tool call.
+    pub fn on_event(mut self, on_event: impl FnMut(&ToolRunEvent) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(on_event));
+        self
+    }
+
+    /// Runs the conversation of `builder` to its end.
+    ///
+    /// The registered tools are added to the tools already set on the builder.
+    /// Fails with [`ToolRunError::MultipleChoices`] if the builder sets `n` above 1.
+    pub async fn run(
+        &mut self,
+        mut builder: ChatCompletionBuilder,
+    ) -> Result<ToolRunOutput, ToolRunError> {
+        if let Some(Some(n)) = builder.n {
+            if n > 1 {
+                return Err(ToolRunError::MultipleChoices(n));
+            }
+        }
+        let mut tools = builder.tools.take().unwrap_or_default();
+        tools.extend(self.registry.definitions());
+        builder.tools = Some(tools);
+        let mut messages = builder.messages.take().unwrap_or_default();
+
+        for iteration in 1..=self.max_iterations {
+            let completion = builder.clone().messages(messages.clone()).create().await?;
+            self.emit(ToolRunEvent::Completion {
+                iteration,
+                completion: &completion,
+            });
+            let message = completion
+                .choices
+                .first()
+                .ok_or(ToolRunError::NoChoices)?
+                .message
+                .clone();
+            let tool_calls = message.tool_calls.clone().unwrap_or_default();
+            messages.push(message);
+            if tool_calls.is_empty() {
+                return Ok(ToolRunOutput {
+                    completion,
+                    messages,
+                    iterations: iteration,
+                });
+            }
+
+            // Events are emitted as the calls finish, the answers keep the call order.
+            let mut outcomes = vec![None; tool_calls.len()];
+            if self.parallel {
+                let mut calls: FuturesUnordered<_> = tool_calls
+                    .iter()
+                    .enumerate()
+                    .map(|(index, call)| self.call(call).map(move |outcome| (index, outcome)))
+                    .collect();
+                while let Some((index, outcome)) = calls.next().await {
+                    self.finish(iteration, &tool_calls[index], &outcome);
+                    outcomes[index] = Some(outcome);
+                }
+            } else {
+                for (index, tool_call) in tool_calls.iter().enumerate() {
+                    let outcome = self.call(tool_call).await;
+                    self.finish(iteration, tool_call, &outcome);
+                    outcomes[index] = Some(outcome);
+                }
+            }
+            for (tool_call, outcome) in tool_calls.iter().zip(outcomes) {
+                let outcome = outcome.expect("every tool call has finished");
+                messages.push(ChatCompletionMessage {
+                    role: ChatCompletionMessageRole::Tool,
+                    content: Some(outcome.to_content().into()),
+                    tool_call_id: Some(tool_call.id.clone()),
+                    ..Default::default()
+                });
+            }
+        }
+        Err(ToolRunError::MaxIterations { messages })
+    }
+
+    fn call(&self, tool_call: &ToolCall) -> BoxFuture<'static, ToolCallOutcome> {
+        let Some(tool) = self.registry.get(&tool_call.function.name) else {
+            return async { ToolCallOutcome::UnknownTool }.boxed();
+        };
+        let future = (tool.handler)(tool_call.function.arguments.clone());
+        match tool.timeout.or(self.timeout) {
+            Some(timeout) => async move {
+                tokio::time::timeout(timeout, future)
+                    .await
+                    .unwrap_or(ToolCallOutcome::TimedOut(timeout))
+            }
+            .boxed(),
+            None => future,
+        }
+    }
+
+    fn finish(&mut self, iteration: usize, tool_call: &ToolCall, outcome: &ToolCallOutcome) {
+        self.emit(ToolRunEvent::ToolCallFinished {
+            iteration,
+            tool_call,
+            outcome,
+        });
+    }
+
+    fn emit(&mut self, event: ToolRunEvent) {
+        if let Some(on_event) = self.on_event.as_mut() {
+            on_event(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{json_response, serve_responses};
+    use serde::Deserialize;
+    use std::sync::Mutex;
+
+    /// Adds two numbers.
+    #[derive(JsonSchema, Deserialize)]
+    struct Add {
+        a: i64,
+        b: i64,
+    }
+
+    /// Waits forever.
+    #[derive(JsonSchema, Deserialize)]
+    struct Hang {}
+
+    #[derive(JsonSchema, Deserialize, Debug)]
+    enum Unit {
+        Celsius,
+        Fahrenheit,
+    }
+
+    /// Gets the current weather.
+    ///
+    /// Only knows about Paris.
+    ///
+    /// # Arguments
+    ///
+    /// * `location` - The city,
+    ///   e.g. Paris.
+    /// - r#unit: The unit of the temperature.
+    #[crate::tool]
+    async fn get_weather(location: String, r#unit: Option<Unit>) -> Result<String, std::io::Error> {
+        match location.as_str() {
+            "Paris" => Ok(format!("22 degrees {unit:?}")),
+            _ => Err(std::io::Error::other(format!("unknown city {location}"))),
+        }
+    }
+
+    #[crate::tool]
+    fn count(items: Vec<String>) -> usize {
+        items.len()
+    }
+
+    #[crate::tool]
+    fn total(prices: std::collections::HashMap<String, u32>, range: (u32, u32)) -> u32 {
+        prices
+            .values()
+            .filter(|price| (range.0..=range.1).contains(*price))
+            .sum()
+    }
+
+    fn completion_json(message: Value) -> String {
+        serde_json::json!({
+            "id": "c",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "m",
+            "choices": [{ "index": 0, "finish_reason": "stop", "message": message }]
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn tool_runner() {
+        let tool_calls = serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [
+                { "id": "1", "type": "function", "function": { "name": "Add", "arguments": "{\"a\":1,\"b\":2}" } },
+                { "id": "2", "type": "function", "function": { "name": "Add", "arguments": "{\"a\":\"x\"}" } },
+                { "id": "3", "type": "function", "function": { "name": "Hang", "arguments": "{}" } },
+                { "id": "4", "type": "function", "function": { "name": "Missing", "arguments": "{}" } }
+            ]
+        });
+        let answer = serde_json::json!({ "role": "assistant", "content": "3" });
+        let credentials = serve_responses(vec![
+            json_response(&completion_json(tool_calls)),
+            json_response(&completion_json(answer)),
+        ])
+        .await;
+
+        let mut registry = ToolRegistry::new();
+        registry
+            .register(|args: Add| async move { Ok(args.a + args.b) })
+            .register(|_: Hang| async move {
+                std::future::pending::<()>().await;
+                Ok(())
+            })
+            .set_timeout("Hang", Duration::from_millis(10))
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let output = ToolRunner::new(registry)
+            .on_event(move |event| {
+                let event = match event {
+                    ToolRunEvent::Completion { iteration, .. } => format!("completion {iteration}"),
+                    ToolRunEvent::ToolCallFinished { tool_call, .. } => {
+                        format!("tool {}", tool_call.id)
+                    }
+                };
+                events_clone.lock().unwrap().push(event);
+            })
+            .run(ChatCompletion::builder("m", []).credentials(credentials))
+            .await
+            .unwrap();
+
+        assert_eq!(output.iterations, 2);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "completion 1",
+                "tool 1",
+                "tool 2",
+                "tool 4",
+                "tool 3",
+                "completion 2"
+            ]
+        );
+        let tool_messages: Vec<_> = output
+            .messages
+            .iter()
+            .filter(|message| message.role == ChatCompletionMessageRole::Tool)
+            .map(|message| {
+                (
+                    message.tool_call_id.clone().unwrap(),
+                    message.content.as_ref().unwrap().to_text(),
+                )
+            })
+            .collect();
+        assert_eq!(tool_messages[0], ("1".to_string(), "3".to_string()));
+        assert!(tool_messages[1].1.starts_with("Error: invalid arguments"));
+        assert!(tool_messages[2].1.contains("did not finish"));
+        assert_eq!(tool_messages[3].1, "Error: no tool with this name exists");
+        assert_eq!(output.messages.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn tool_runner_rejects_multiple_choices() {
+        let error = ToolRunner::new(ToolRegistry::new())
+            .run(ChatCompletion::builder("m", []).n(2))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ToolRunError::MultipleChoices(2)));
+    }
+
+    #[test]
+    fn set_timeout_of_unknown_tool() {
+        let mut registry = ToolRegistry::new();
+        registry.register(|args: Add| async move { Ok(args.a + args.b) });
+        let error = registry
+            .set_timeout("Sub", Duration::from_secs(1))
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "No tool named `Sub` is registered");
+        assert!(registry.set_timeout("Add", Duration::from_secs(1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn tool_macro() {
+        let ChatCompletionTool::Function { function } = get_weather::tool();
+        assert_eq!(function.name, "get_weather");
+        assert_eq!(
+            function.description.as_deref(),
+            Some("Gets the current weather. Only knows about Paris.")
+        );
+        let parameters = function.parameters.unwrap();
+        assert_eq!(
+            parameters["properties"]["location"]["description"],
+            "The city, e.g. Paris."
+        );
+        assert_eq!(
+            parameters["properties"]["unit"]["description"],
+            "The unit of the temperature."
+        );
+
+        let call = |arguments: &str| crate::chat::ToolCallFunction {
+            name: "get_weather".to_string(),
+            arguments: arguments.to_string(),
+        };
+        let output = get_weather::dispatch(&call(r#"{"location":"Paris","unit":"Celsius"}"#))
+            .await
+            .unwrap();
+        assert_eq!(output, "22 degrees Some(Celsius)");
+        let error = get_weather::dispatch(&call(r#"{"location":"Rome","unit":null}"#))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "unknown city Rome");
+        assert!(get_weather::dispatch(&call("{}")).await.is_err());
+
+        // Maps and tuples are sent as entries and objects, following the schema.
+        let arguments =
+            r#"{"prices":[{"key":"a","value":2},{"key":"b","value":9}],"range":{"0":1,"1":5}}"#;
+        assert_eq!(total::dispatch(&call(arguments)).await.unwrap(), 2);
+
+        let mut registry = ToolRegistry::new();
+        count::register(get_weather::register(&mut registry));
+        assert_eq!(registry.definitions(), [get_weather::tool(), count::tool()]);
+        let arguments = count::Arguments {
+            items: vec!["a".to_string()],
+        };
+        assert_eq!(count::invoke(arguments).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,574 @@
+//! A small JSON Schema validator, for the subset of JSON Schema the generated
+//! schemas use.
+//!
+//! Supports `type`, `nullable`, `enum`, `const`, `properties`, `required`,
+//! `additionalProperties`, `items`, `anyOf`, `oneOf`, `allOf`, local `$ref`s, the
+//! length and range keywords, `pattern`, and the `date`, `date-time`, `email` and
+//! `uuid` formats. Other keywords and formats are ignored, as are the patterns the
+//! `regex` crate cannot compile, like the lookarounds of ECMA-262.
+//!
+//! ```
+//! use openai::chat::validator::validate;
+//! use serde_json::json;
+//!
+//! let schema = json!({
+//!     "type": "object",
+//!     "properties": { "value": { "type": "integer", "minimum": 0 } },
+//!     "required": ["value"]
+//! });
+//! assert!(validate(&schema, &json!({ "value": 3 })).is_empty());
+//! let violations = validate(&schema, &json!({ "value": -1 }));
+//! assert_eq!(violations[0].to_string(), "/value: -1 is less than the minimum of 0");
+//! ```
+
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// A value not matching its schema.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SchemaViolation {
+    /// The JSON pointer of the value, empty for the root.
+    pub pointer: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.pointer.is_empty() {
+            write!(f, "(root): {}", self.message)
+        } else {
+            write!(f, "{}: {}", self.pointer, self.message)
+        }
+    }
+}
+
+/// Validates `instance` against `schema`, returning every violation.
+pub fn validate(schema: &Value, instance: &Value) -> Vec<SchemaViolation> {
+    let patterns = RefCell::default();
+    let mut validator = Validator {
+        root: schema,
+        patterns: &patterns,
+        violations: Vec::new(),
+    };
+    validator.validate(schema, instance, String::new());
+    validator.violations
+}
+
+/// Whether `instance` is valid against `schema`, a subschema of `root` which its
+/// `$ref`s are resolved in.
+pub(crate) fn is_valid(root: &Value, schema: &Value, instance: &Value) -> bool {
+    Validator {
+        root,
+        patterns: &RefCell::default(),
+        violations: Vec::new(),
+    }
+    .is_valid(schema, instance)
+}
+
+/// The maximum depth of `$ref`s, to stop on schemas referencing themselves
+/// without consuming the instance.
+const MAX_REF_DEPTH: usize = 64;
+
+struct Validator<'a> {
+    root: &'a Value,
+    /// The compiled patterns, `None` for the ones which do not compile.
+    patterns: &'a RefCell<HashMap<String, Option<Regex>>>,
+    violations: Vec<SchemaViolation>,
+}
+
+impl Validator<'_> {
+    fn report(&mut self, pointer: &str, message: String) {
+        self.violations.push(SchemaViolation {
+            pointer: pointer.to_string(),
+            message,
+        });
+    }
+
+    /// Whether `string` matches `pattern`, or `None` when the pattern does not compile.
+    fn matches(&self, pattern: &str, string: &str) -> Option<bool> {
+        let mut patterns = self.patterns.borrow_mut();
+        let regex = patterns
+            .entry(pattern.to_string())
+            .or_insert_with(|| Regex::new(pattern).ok());
+        regex.as_ref().map(|regex| regex.is_match(string))
+    }
+
+    fn is_valid(&self, schema: &Value, instance: &Value) -> bool {
+        let mut validator = Validator {
+            root: self.root,
+            patterns: self.patterns,
+            violations: Vec::new(),
+        };
+        validator.validate(schema, instance, String::new());
+        validator.violations.is_empty()
+    }
+
+    fn validate(&mut self, schema: &Value, instance: &Value, pointer: String) {
+        let mut schema = schema;
+        for _ in 0..MAX_REF_DEPTH {
+            match schema.get("$ref").and_then(Value::as_str) {
+                Some(reference) => match resolve(self.root, reference) {
+                    Some(resolved) => schema = resolved,
+                    None => {
+                        return self.report(&pointer, format!("unresolved `$ref` `{reference}`"))
+                    }
+                },
+                None => break,
+            }
+        }
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return self.report(&pointer, "no value is allowed".to_string()),
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if instance.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+            return;
+        }
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::String(r#type) => vec![r#type.as_str()],
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !types.iter().any(|r#type| has_type(instance, r#type)) {
+                let message = format!("expected {}, got {}", types.join(" or "), kind(instance));
+                return self.report(&pointer, message);
+            }
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            if !values.contains(instance) {
+                self.report(
+                    &pointer,
+                    format!("{instance} is not one of {}", Value::from(values.clone())),
+                );
+            }
+        }
+        if let Some(value) = schema.get("const") {
+            if value != instance {
+                self.report(&pointer, format!("expected {value}, got {instance}"));
+            }
+        }
+
+        self.validate_composition(schema, instance, &pointer);
+        match instance {
+            Value::Object(object) => self.validate_object(schema, object, &pointer),
+            Value::Array(array) => self.validate_array(schema, array, &pointer),
+            Value::String(string) => {
+                let length = string.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                    if length < min {
+                        self.report(&pointer, format!("shorter than {min} characters"));
+                    }
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                    if length > max {
+                        self.report(&pointer, format!("longer than {max} characters"));
+                    }
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                    if self.matches(pattern, string) == Some(false) {
+                        self.report(&pointer, format!("does not match the pattern `{pattern}`"));
+                    }
+                }
+                if let Some(format) = schema.get("format").and_then(Value::as_str) {
+                    if !has_format(string, format) {
+                        self.report(&pointer, format!("not a valid {format}"));
+                    }
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+                if let Some(min) = bound("minimum").filter(|min| number < *min) {
+                    self.report(
+                        &pointer,
+                        format!("{instance} is less than the minimum of {min}"),
+                    );
+                }
+                if let Some(max) = bound("maximum").filter(|max| number > *max) {
+                    self.report(
+                        &pointer,
+                        format!("{instance} is greater than the maximum of {max}"),
+                    );
+                }
+                if let Some(min) = bound("exclusiveMinimum").filter(|min| number <= *min) {
+                    self.report(&pointer, format!("{instance} is not greater than {min}"));
+                }
+                if let Some(max) = bound("exclusiveMaximum").filter(|max| number >= *max) {
+                    self.report(&pointer, format!("{instance} is not less than {max}"));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn validate_composition(
+        &mut self,
+        schema: &Map<String, Value>,
+        instance: &Value,
+        pointer: &str,
+    ) {
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            for schema in schemas {
+                self.validate(schema, instance, pointer.to_string());
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+            if !schemas.iter().any(|schema| self.is_valid(schema, instance)) {
+                self.report(
+                    pointer,
+                    format!("does not match any of the {} variants", schemas.len()),
+                );
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+            let matching = schemas
+                .iter()
+                .filter(|schema| self.is_valid(schema, instance))
+                .count();
+            if matching != 1 {
+                let message = format!(
+                    "matches {matching} of the {} variants instead of one",
+                    schemas.len()
+                );
+                self.report(pointer, message);
+            }
+        }
+    }
+
+    fn validate_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        pointer: &str,
+    ) {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    self.report(pointer, format!("missing required property `{name}`"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, value) in object {
+            let property_pointer = format!("{pointer}/{}", escape(name));
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => self.validate(property, value, property_pointer),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        self.report(pointer, format!("unexpected property `{name}`"));
+                    }
+                    Some(additional @ Value::Object(_)) => {
+                        self.validate(additional, value, property_pointer);
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    fn validate_array(&mut self, schema: &Map<String, Value>, array: &[Value], pointer: &str) {
+        let length = array.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if length < min {
+                self.report(pointer, format!("fewer than {min} items"));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if length > max {
+                self.report(pointer, format!("more than {max} items"));
+            }
+        }
+        if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+            let duplicate = array
+                .iter()
+                .enumerate()
+                .any(|(index, item)| array[..index].contains(item));
+            if duplicate {
+                self.report(pointer, "items are not unique".to_string());
+            }
+        }
+        match schema.get("items") {
+            // A tuple, before `prefixItems`.
+            Some(Value::Array(items)) => {
+                for (index, (item, value)) in items.iter().zip(array).enumerate() {
+                    self.validate(item, value, format!("{pointer}/{index}"));
+                }
+            }
+            Some(items) => {
+                for (index, value) in array.iter().enumerate() {
+                    self.validate(items, value, format!("{pointer}/{index}"));
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+fn has_type(instance: &Value, r#type: &str) -> bool {
+    match r#type {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        "integer" => {
+            instance.is_i64()
+                || instance.is_u64()
+                || instance
+                    .as_f64()
+                    .is_some_and(|number| number.fract() == 0.0)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `string` is valid in `format`. Unknown formats accept every string.
+fn has_format(string: &str, format: &str) -> bool {
+    match format {
+        "date" => is_date(string),
+        "date-time" => is_date_time(string),
+        "email" => is_email(string),
+        "uuid" => is_uuid(string),
+        _ => true,
+    }
+}
+
+/// Parses `count` ASCII digits at the start of `string`.
+fn digits(string: &str, count: usize) -> Option<u32> {
+    let digits = string.get(..count)?;
+    if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// A full date like `2024-02-29`.
+fn is_date(string: &str) -> bool {
+    let bytes = string.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return false;
+    }
+    let (Some(year), Some(month), Some(day)) = (
+        digits(string, 4),
+        digits(&string[5..], 2),
+        digits(&string[8..], 2),
+    ) else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+/// An RFC 3339 date and time like `2024-02-29T13:45:00.5+01:00`.
+fn is_date_time(string: &str) -> bool {
+    let Some((date, time)) = string.split_once(['T', 't', ' ']) else {
+        return false;
+    };
+    if !is_date(date) {
+        return false;
+    }
+    let bytes = time.as_bytes();
+    if bytes.len() < 8 || bytes[2] != b':' || bytes[5] != b':' {
+        return false;
+    }
+    let (Some(hour), Some(minute), Some(second)) = (
+        digits(time, 2),
+        digits(&time[3..], 2),
+        digits(&time[6..], 2),
+    ) else {
+        return false;
+    };
+    if hour > 23 || minute > 59 || second > 60 {
+        return false;
+    }
+    let mut offset = &time[8..];
+    if let Some(fraction) = offset.strip_prefix('.') {
+        let length = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if length == 0 {
+            return false;
+        }
+        offset = &fraction[length..];
+    }
+    match offset.as_bytes() {
+        [b'Z' | b'z'] => true,
+        [b'+' | b'-', _, _, b':', _, _] => {
+            matches!((digits(&offset[1..], 2), digits(&offset[4..], 2)), (Some(hour), Some(minute)) if hour < 24 && minute < 60)
+        }
+        _ => false,
+    }
+}
+
+/// An email address like `name@example.com`, without quoted local parts.
+fn is_email(string: &str) -> bool {
+    let Some((local, domain)) = string.split_once('@') else {
+        return false;
+    };
+    let is_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    !local.is_empty()
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-/=?^_`{|}~.".contains(&b))
+        && domain.contains('.')
+        && domain.split('.').all(is_label)
+}
+
+/// A UUID like `123e4567-e89b-12d3-a456-426614174000`.
+fn is_uuid(string: &str) -> bool {
+    string.len() == 36
+        && string.bytes().enumerate().all(|(index, byte)| match index {
+            8 | 13 | 18 | 23 => byte == b'-',
+            _ => byte.is_ascii_hexdigit(),
+        })
+}
+
+fn kind(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Resolves a `$ref` local to the root schema, like `#/$defs/Name`.
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    root.pointer(reference.strip_prefix('#')?)
+}
+
+/// Escapes a key as a JSON pointer token.
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn messages(schema: &Value, instance: &Value) -> Vec<String> {
+        validate(schema, instance)
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn validate_instances() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "maxLength": 3 },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "uniqueItems": true },
+                "parent": { "anyOf": [{ "$ref": "#" }, { "type": "null" }] },
+                "score": { "type": ["integer", "null"], "maximum": 10 },
+                "kind": { "const": "node" }
+            },
+            "required": ["name", "kind"],
+            "additionalProperties": false
+        });
+        let valid = json!({
+            "name": "abc",
+            "tags": ["a", "b"],
+            "parent": { "name": "r", "kind": "node", "parent": null },
+            "score": 10.0,
+            "kind": "node"
+        });
+        assert_eq!(messages(&schema, &valid), Vec::<String>::new());
+
+        let invalid = json!({
+            "name": "abcd",
+            "tags": ["a", "c", "a"],
+            "parent": { "name": 1, "kind": "node" },
+            "score": 1.5,
+            "extra": true
+        });
+        assert_eq!(
+            messages(&schema, &invalid),
+            [
+                "(root): missing required property `kind`",
+                "(root): unexpected property `extra`",
+                "/name: longer than 3 characters",
+                "/parent: does not match any of the 2 variants",
+                "/score: expected integer or null, got number",
+                "/tags: items are not unique",
+                "/tags/1: \"c\" is not one of [\"a\",\"b\"]",
+            ]
+        );
+
+        let formats = json!({
+            "type": "object",
+            "properties": {
+                "code": { "type": "string", "pattern": "^[A-Z]{3}$" },
+                // A lookahead, which the `regex` crate does not support.
+                "pin": { "type": "string", "pattern": "^(?=\\d).+$" },
+                "date": { "type": "string", "format": "date" },
+                "time": { "type": "string", "format": "date-time" },
+                "email": { "type": "string", "format": "email" },
+                "id": { "type": "string", "format": "uuid" },
+                "other": { "type": "string", "format": "hostname" }
+            }
+        });
+        let valid = json!({
+            "code": "ABC",
+            "date": "2024-02-29",
+            "time": "2024-02-29T13:45:00.5+01:00",
+            "email": "first.last+tag@example.co.uk",
+            "id": "123e4567-E89B-12d3-a456-426614174000",
+            "other": "not checked"
+        });
+        assert_eq!(messages(&formats, &valid), Vec::<String>::new());
+        let invalid = json!({
+            "code": "ABCD",
+            "pin": "not checked",
+            "date": "2023-02-29",
+            "time": "2024-02-29 24:00:00Z",
+            "email": "first@last@example.com",
+            "id": "123e4567e89b12d3a456426614174000"
+        });
+        assert_eq!(
+            messages(&formats, &invalid),
+            [
+                "/code: does not match the pattern `^[A-Z]{3}$`",
+                "/date: not a valid date",
+                "/email: not a valid email",
+                "/id: not a valid uuid",
+                "/time: not a valid date-time",
+            ]
+        );
+        assert!(is_date_time("2024-01-01t00:00:00Z"));
+        assert!(!is_date_time("2024-01-01T00:00:00"));
+        assert!(!is_email("name@localhost"));
+
+        let nullable = json!({ "type": "string", "nullable": true });
+        assert_eq!(messages(&nullable, &Value::Null), Vec::<String>::new());
+        assert_eq!(
+            messages(&json!({ "$ref": "#/$defs/missing" }), &json!(1)),
+            ["(root): unresolved `$ref` `#/$defs/missing`"]
+        );
+    }
+}
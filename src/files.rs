@@ -288,6 +288,9 @@ impl Files {
     pub fn len(&self) -> usize {
         self.data.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 }
 
 impl<'a> IntoIterator for &'a Files {
@@ -300,6 +303,11 @@ impl<'a> IntoIterator for &'a Files {
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::await_holding_lock,
+    clippy::len_zero,
+    clippy::unnecessary_sort_by
+)]
 mod tests {
     use std::env;
     use std::io::Read;
@@ -1,10 +1,13 @@
+use futures_util::stream::{self, BoxStream};
+use futures_util::StreamExt;
 use reqwest::multipart::Form;
 use reqwest::{header::AUTHORIZATION, Client, Method, RequestBuilder, Response};
-use reqwest_eventsource::{CannotCloneRequestError, EventSource, RequestBuilderExt};
+use reqwest_eventsource::{CannotCloneRequestError, Event, EventSource, RequestBuilderExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::env;
 use std::env::VarError;
 use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
 
 pub mod chat;
 pub mod completions;
@@ -13,6 +16,73 @@ pub mod embeddings;
 pub mod files;
 pub mod models;
 pub mod moderations;
+#[cfg(feature = "tokenizer")]
+pub mod tokenizer;
+
+// Lets the code generated by the macros refer to `::openai` inside this crate too.
+extern crate self as openai;
+
+/// Turns a function into a chat tool, generating a module of the same name with:
+///
+/// - `Arguments`, a struct with a field for each parameter, described by the doc
+///   comment of the function and the `# Arguments` section of it;
+/// - `tool()`, the [`ChatCompletionTool`](chat::ChatCompletionTool) of the function,
+///   named after it;
+/// - `dispatch(&ToolCallFunction)`, which deserializes the arguments of a tool call
+///   and calls the function, and `invoke(Arguments)`;
+/// - `register(&mut ToolRegistry)`, to run the tool with a
+///   [`ToolRunner`](chat::tools::ToolRunner).
+///
+/// The function may be async, and return a `Result` whose error converts into an
+/// [`anyhow::Error`]. Its parameters must be deserializable and implement
+/// `JsonSchema`.
+///
+/// ```
+/// use openai::chat::ToolCallFunction;
+/// use schemars::JsonSchema;
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize, JsonSchema)]
+/// enum Unit {
+///     Celsius,
+///     Fahrenheit,
+/// }
+///
+/// /// Gets the current weather.
+/// ///
+/// /// # Arguments
+/// ///
+/// /// * `location` - The city, e.g. Paris.
+/// /// * `unit` - The unit of the temperature.
+/// #[openai::tool]
+/// async fn get_weather(location: String, unit: Unit) -> anyhow::Result<String> {
+///     Ok(format!("22 degrees in {location}"))
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let openai::chat::ChatCompletionTool::Function { function } = get_weather::tool();
+/// assert_eq!(function.name, "get_weather");
+/// assert_eq!(function.description.as_deref(), Some("Gets the current weather."));
+///
+/// let call = ToolCallFunction {
+///     name: "get_weather".to_string(),
+///     arguments: r#"{"location":"Paris","unit":"Celsius"}"#.to_string(),
+/// };
+/// assert_eq!(get_weather::dispatch(&call).await?, "22 degrees in Paris");
+/// # Ok(())
+/// # }
+/// ```
+pub use openai_macros::tool;
+
+/// The dependencies of the code generated by the macros.
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow;
+    pub use schemars;
+    pub use serde;
+    pub use serde_json;
+}
 
 pub static DEFAULT_BASE_URL: LazyLock<String> =
     LazyLock::new(|| String::from("https://api.openai.com/v1/"));
@@ -107,10 +177,107 @@ pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    /// Breakdown of the tokens used in the prompt.
+    #[serde(default)]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    /// Breakdown of the tokens used in the completion.
+    #[serde(default)]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PromptTokensDetails {
+    /// Tokens that were read from the prompt cache.
+    #[serde(default)]
+    pub cached_tokens: Option<u32>,
+    /// Audio input tokens present in the prompt.
+    #[serde(default)]
+    pub audio_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompletionTokensDetails {
+    /// Tokens generated by the model for reasoning.
+    #[serde(default)]
+    pub reasoning_tokens: Option<u32>,
+    /// Audio tokens generated by the model.
+    #[serde(default)]
+    pub audio_tokens: Option<u32>,
+    /// Tokens of a predicted output that appeared in the completion.
+    #[serde(default)]
+    pub accepted_prediction_tokens: Option<u32>,
+    /// Tokens of a predicted output that did not appear in the completion.
+    /// They are still counted and billed like other completion tokens.
+    #[serde(default)]
+    pub rejected_prediction_tokens: Option<u32>,
 }
 
 pub type ApiResponseOrError<T> = Result<T, OpenAiError>;
 
+/// An error yielded by a response stream. The stream ends after yielding it.
+#[derive(Debug)]
+pub enum StreamError {
+    /// The API responded with an error, either instead of the stream or inside of it.
+    Api(OpenAiError),
+    /// The connection failed or the event stream could not be read.
+    Transport(reqwest_eventsource::Error),
+    /// A streamed chunk could not be deserialized.
+    Deserialize {
+        error: serde_json::Error,
+        data: String,
+    },
+    /// The stream failed after some chunks were already received.
+    ///
+    /// Streams are not reconnected at this point; what was received so far stays
+    /// valid and can be used to resume, see [`StreamRetryPolicy`].
+    Interrupted {
+        error: Box<StreamError>,
+        chunks_received: usize,
+    },
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Api(error) => write!(f, "API error: {error}"),
+            StreamError::Transport(error) => write!(f, "Stream transport error: {error}"),
+            StreamError::Deserialize { error, data } => {
+                write!(f, "Failed to deserialize stream chunk `{data}`: {error}")
+            }
+            StreamError::Interrupted {
+                error,
+                chunks_received,
+            } => write!(
+                f,
+                "Stream interrupted after {chunks_received} chunks: {error}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StreamError::Api(error) => Some(error),
+            StreamError::Transport(error) => Some(error),
+            StreamError::Deserialize { error, .. } => Some(error),
+            StreamError::Interrupted { error, .. } => Some(error.as_ref()),
+        }
+    }
+}
+
+impl From<OpenAiError> for StreamError {
+    fn from(value: OpenAiError) -> Self {
+        StreamError::Api(value)
+    }
+}
+
+impl From<CannotCloneRequestError> for StreamError {
+    fn from(value: CannotCloneRequestError) -> Self {
+        StreamError::Api(OpenAiError::new(value.to_string(), "reqwest".to_string()))
+    }
+}
+
 impl From<reqwest::Error> for OpenAiError {
     fn from(value: reqwest::Error) -> Self {
         OpenAiError::new(value.to_string(), "reqwest".to_string())
@@ -164,24 +331,209 @@ where
     Ok(response)
 }
 
-async fn openai_request_stream<F>(
+/// Controls whether a response stream is retried when it fails.
+///
+/// Streams are never reconnected once a chunk was received: for generation endpoints
+/// a reconnect runs the generation again, and the new chunks would be interleaved
+/// with the old ones. Failures before the first chunk, such as connection errors or
+/// `429` and `5xx` responses, may be retried. Failures after it end the stream with
+/// [`StreamError::Interrupted`], leaving what was received so far to the caller.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StreamRetryPolicy {
+    /// How many times to retry before the first chunk was received.
+    pub max_retries: u32,
+    /// The delay before the first retry. It doubles with every following retry.
+    pub initial_delay: Duration,
+    /// The upper bound for the delay between retries.
+    pub max_delay: Duration,
+}
+
+impl StreamRetryPolicy {
+    /// Never retries, which is the default.
+    pub const fn never() -> Self {
+        StreamRetryPolicy {
+            max_retries: 0,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+
+    /// Retries up to `max_retries` times, as long as no chunk was received.
+    pub const fn before_first_chunk(max_retries: u32) -> Self {
+        StreamRetryPolicy {
+            max_retries,
+            ..Self::never()
+        }
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay)
+    }
+}
+
+impl Default for StreamRetryPolicy {
+    fn default() -> Self {
+        Self::never()
+    }
+}
+
+async fn openai_request_stream<F, T>(
     method: Method,
     route: &str,
     builder: F,
     credentials_opt: Option<Credentials>,
-) -> Result<EventSource, CannotCloneRequestError>
+    retry_policy: StreamRetryPolicy,
+) -> Result<BoxStream<'static, Result<T, StreamError>>, StreamError>
 where
     F: FnOnce(RequestBuilder) -> RequestBuilder,
+    T: DeserializeOwned + Send + 'static,
 {
     let client = Client::new();
     let credentials =
         credentials_opt.unwrap_or_else(|| DEFAULT_CREDENTIALS.read().unwrap().clone());
     let mut request = client.request(method, format!("{}{route}", credentials.base_url));
     request = builder(request);
-    let stream = request
-        .header(AUTHORIZATION, format!("Bearer {}", credentials.api_key))
+    let request = request.header(AUTHORIZATION, format!("Bearer {}", credentials.api_key));
+    let state = EventStreamState {
+        source: event_source(&request)?,
+        request,
+        retry_policy,
+        retries: 0,
+        chunks_received: 0,
+    };
+    Ok(deserialize_event_stream(state))
+}
+
+/// Opens an event source that does not reconnect by itself.
+fn event_source(request: &RequestBuilder) -> Result<EventSource, CannotCloneRequestError> {
+    let mut source = request
+        .try_clone()
+        .ok_or(CannotCloneRequestError)?
         .eventsource()?;
-    Ok(stream)
+    source.set_retry_policy(Box::new(reqwest_eventsource::retry::Never));
+    Ok(source)
+}
+
+struct EventStreamState {
+    request: RequestBuilder,
+    source: EventSource,
+    retry_policy: StreamRetryPolicy,
+    retries: u32,
+    chunks_received: usize,
+}
+
+/// Deserializes the messages of an event source until the `[DONE]` sentinel.
+///
+/// A stream that closes before the sentinel ends with an error, wrapped in
+/// [`StreamError::Interrupted`] if chunks were already received.
+///
+/// The stream owns the event source, so dropping it aborts the underlying request.
+fn deserialize_event_stream<T>(
+    state: EventStreamState,
+) -> BoxStream<'static, Result<T, StreamError>>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            // The event source ends the stream with `StreamEnded` after closing, or
+            // yields nothing at all. Either way the `[DONE]` sentinel is missing.
+            let event = state
+                .source
+                .next()
+                .await
+                .unwrap_or(Err(reqwest_eventsource::Error::StreamEnded));
+            let error = match event {
+                Ok(Event::Open) => continue,
+                Ok(Event::Message(message)) => {
+                    if message.data == "[DONE]" {
+                        state.source.close();
+                        return None;
+                    }
+                    match serde_json::from_str::<ApiResponse<T>>(&message.data) {
+                        Ok(ApiResponse::Ok(item)) => {
+                            state.chunks_received += 1;
+                            return Some((Ok(item), Some(state)));
+                        }
+                        Ok(ApiResponse::Err { error }) => StreamError::Api(error),
+                        Err(error) => StreamError::Deserialize {
+                            error,
+                            data: message.data,
+                        },
+                    }
+                }
+                Err(reqwest_eventsource::Error::StreamEnded) => {
+                    StreamError::Transport(reqwest_eventsource::Error::StreamEnded)
+                }
+                Err(error) => {
+                    let retry = state.chunks_received == 0
+                        && state.retries < state.retry_policy.max_retries
+                        && is_retryable(&error);
+                    if retry {
+                        state.source.close();
+                        tokio::time::sleep(state.retry_policy.delay(state.retries)).await;
+                        state.retries += 1;
+                        match event_source(&state.request) {
+                            Ok(source) => {
+                                state.source = source;
+                                continue;
+                            }
+                            Err(error) => return Some((Err(error.into()), None)),
+                        }
+                    }
+                    stream_error_from_event_source(error).await
+                }
+            };
+            state.source.close();
+            let error = if state.chunks_received > 0 {
+                StreamError::Interrupted {
+                    error: Box::new(error),
+                    chunks_received: state.chunks_received,
+                }
+            } else {
+                error
+            };
+            return Some((Err(error), None));
+        }
+    })
+    .boxed()
+}
+
+fn is_retryable(error: &reqwest_eventsource::Error) -> bool {
+    match error {
+        reqwest_eventsource::Error::Transport(_) => true,
+        reqwest_eventsource::Error::InvalidStatusCode(status, _) => {
+            status.as_u16() == 429 || status.is_server_error()
+        }
+        _ => false,
+    }
+}
+
+/// Reads the API error out of a rejected stream response when there is one.
+async fn stream_error_from_event_source(error: reqwest_eventsource::Error) -> StreamError {
+    match error {
+        reqwest_eventsource::Error::InvalidStatusCode(_, response)
+        | reqwest_eventsource::Error::InvalidContentType(_, response) => {
+            let status = response.status();
+            let body = match response.text().await {
+                Ok(body) => body,
+                Err(error) => {
+                    return StreamError::Transport(reqwest_eventsource::Error::Transport(error))
+                }
+            };
+            match serde_json::from_str::<ApiResponse<serde_json::Value>>(&body) {
+                Ok(ApiResponse::Err { error }) => StreamError::Api(error),
+                _ => StreamError::Api(OpenAiError::new(
+                    format!("unexpected stream response ({status}): {body}"),
+                    "http".to_string(),
+                )),
+            }
+        }
+        error => StreamError::Transport(error),
+    }
 }
 
 async fn openai_get<T>(route: &str, credentials_opt: Option<Credentials>) -> ApiResponseOrError<T>
@@ -295,5 +647,56 @@ fn parse_base_url(mut value: String) -> String {
 
 #[cfg(test)]
 pub mod tests {
+    use super::Credentials;
+    use std::sync::{Arc, Mutex};
+
     pub const DEFAULT_LEGACY_MODEL: &str = "gpt-3.5-turbo-instruct";
+
+    /// Serves each of the given raw HTTP responses to one connection, in order.
+    pub async fn serve_responses(responses: Vec<String>) -> Credentials {
+        serve_responses_recording(responses).await.0
+    }
+
+    /// Same as [`serve_responses`], also recording the request line of each request,
+    /// e.g. `GET /v1/models HTTP/1.1`.
+    pub async fn serve_responses_recording(
+        responses: Vec<String>,
+    ) -> (Credentials, Arc<Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 8192];
+                let read = socket.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..read]);
+                let request_line = request.lines().next().unwrap_or_default().to_string();
+                recorded.lock().unwrap().push(request_line);
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        (
+            Credentials::new("test", format!("http://{address}/v1/")),
+            requests,
+        )
+    }
+
+    pub fn sse_response(events: &[&str]) -> String {
+        let body: String = events.iter().map(|e| format!("data: {e}\n\n")).collect();
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    pub fn json_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
 }
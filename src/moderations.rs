@@ -82,6 +82,7 @@ impl ModerationBuilder {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use dotenvy::dotenv;
@@ -0,0 +1,281 @@
+//! Offline token counting with the byte pair encodings of the OpenAI models.
+//!
+//! Requires the `tokenizer` feature, which is not enabled by default.
+//!
+//! ```
+//! use openai::tokenizer::Tokenizer;
+//!
+//! let tokenizer = Tokenizer::for_model("gpt-4o").unwrap();
+//! assert_eq!(tokenizer.count("hello world"), 2);
+//! ```
+
+use crate::chat::conversation::TokenCounter;
+use crate::chat::{
+    ChatCompletionContent, ChatCompletionContentPart, ChatCompletionMessage,
+    ChatCompletionResponseFormat, ChatCompletionTool,
+};
+use std::collections::HashMap;
+use tiktoken_rs::CoreBPE;
+
+/// Every message is wrapped as `<|start|>{role}<|message|>{content}<|end|>`.
+const TOKENS_PER_MESSAGE: usize = 3;
+/// A name is added after the role.
+const TOKENS_PER_NAME: usize = 1;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const TOKENS_PER_REPLY: usize = 3;
+/// The tokens of a low detail image, which is also used for other non-text parts.
+const TOKENS_PER_MEDIA_PART: usize = 85;
+/// The tokens of the section introducing the tool definitions.
+const TOKENS_PER_TOOLS: usize = 12;
+/// The tokens wrapping a single tool definition.
+const TOKENS_PER_TOOL: usize = 8;
+/// The tokens of the section introducing a response format schema.
+const TOKENS_PER_RESPONSE_FORMAT: usize = 10;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Encoding {
+    /// The encoding of `gpt-4`, `gpt-4-turbo`, `gpt-3.5-turbo` and the `text-embedding-3` models.
+    Cl100kBase,
+    /// The encoding of `gpt-4o`, `gpt-4.1`, `o1`, `o3` and later models.
+    O200kBase,
+}
+
+impl Encoding {
+    /// The encoding used by `model`, if it is a known chat model.
+    pub fn for_model(model: &str) -> Option<Self> {
+        match tiktoken_rs::tokenizer::get_tokenizer(model)? {
+            tiktoken_rs::tokenizer::Tokenizer::Cl100kBase => Some(Encoding::Cl100kBase),
+            tiktoken_rs::tokenizer::Tokenizer::O200kBase => Some(Encoding::O200kBase),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes text into tokens, and counts the tokens of chat messages and requests.
+///
+/// Counts of whole requests follow the format the models see. They are exact for
+/// plain text messages, and close estimates for tool and schema definitions, whose
+/// rendering is not documented.
+#[derive(Clone, Copy)]
+pub struct Tokenizer {
+    encoding: Encoding,
+    bpe: &'static CoreBPE,
+}
+
+impl std::fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokenizer")
+            .field("encoding", &self.encoding)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tokenizer {
+    /// The encoding is loaded once, on first use.
+    pub fn new(encoding: Encoding) -> Self {
+        let bpe = match encoding {
+            Encoding::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+            Encoding::O200kBase => tiktoken_rs::o200k_base_singleton(),
+        };
+        Tokenizer { encoding, bpe }
+    }
+
+    /// The tokenizer of `model`, if it is a known chat model.
+    pub fn for_model(model: &str) -> Option<Self> {
+        Encoding::for_model(model).map(Self::new)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Encodes `text`, treating special tokens such as `<|endoftext|>` as plain text.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.bpe.encode_ordinary(text)
+    }
+
+    /// Decodes `tokens`. Fails if a token is unknown or the bytes are not valid UTF-8,
+    /// e.g. because a character is split across the end of `tokens`.
+    pub fn decode(&self, tokens: &[u32]) -> anyhow::Result<String> {
+        self.bpe.decode(tokens.to_vec())
+    }
+
+    /// The number of tokens of `text`.
+    pub fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// The number of tokens of a message, including its formatting overhead.
+    pub fn count_message(&self, message: &ChatCompletionMessage) -> usize {
+        let role = serde_json::to_value(message.role).unwrap_or_default();
+        let mut tokens = TOKENS_PER_MESSAGE + self.count(role.as_str().unwrap_or_default());
+        tokens += match &message.content {
+            Some(ChatCompletionContent::Text(text)) => self.count(text),
+            Some(ChatCompletionContent::Parts(parts)) => parts
+                .iter()
+                .map(|part| match part {
+                    ChatCompletionContentPart::Text { text } => self.count(text),
+                    _ => TOKENS_PER_MEDIA_PART,
+                })
+                .sum(),
+            None => 0,
+        };
+        if let Some(refusal) = &message.refusal {
+            tokens += self.count(refusal);
+        }
+        if let Some(name) = &message.name {
+            tokens += self.count(name) + TOKENS_PER_NAME;
+        }
+        for tool_call in message.tool_calls.iter().flatten() {
+            tokens += self.count(&tool_call.function.name);
+            tokens += self.count(&tool_call.function.arguments);
+        }
+        tokens
+    }
+
+    /// The number of tokens of a list of messages, including the tokens priming the reply.
+    pub fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+        messages
+            .iter()
+            .map(|message| self.count_message(message))
+            .sum::<usize>()
+            + TOKENS_PER_REPLY
+    }
+
+    /// An estimate of the number of tokens taken by tool definitions.
+    pub fn count_tools(&self, tools: &[ChatCompletionTool]) -> usize {
+        if tools.is_empty() {
+            return 0;
+        }
+        TOKENS_PER_TOOLS
+            + tools
+                .iter()
+                .map(|tool| {
+                    let ChatCompletionTool::Function { function } = tool;
+                    let parameters = function.parameters.as_ref().map(|p| p.to_string());
+                    TOKENS_PER_TOOL
+                        + self.count(&function.name)
+                        + self.count(function.description.as_deref().unwrap_or_default())
+                        + self.count(parameters.as_deref().unwrap_or_default())
+                })
+                .sum::<usize>()
+    }
+
+    /// An estimate of the number of tokens taken by a `json_schema` response format.
+    /// Other formats take no tokens.
+    pub fn count_response_format(&self, response_format: &ChatCompletionResponseFormat) -> usize {
+        match response_format {
+            ChatCompletionResponseFormat::JsonSchema { json_schema } => {
+                let schema = json_schema.schema.as_ref().map(|s| s.to_string());
+                TOKENS_PER_RESPONSE_FORMAT
+                    + self.count(&json_schema.name)
+                    + self.count(json_schema.description.as_deref().unwrap_or_default())
+                    + self.count(schema.as_deref().unwrap_or_default())
+            }
+            _ => 0,
+        }
+    }
+
+    /// Builds a `logit_bias` map applying `bias` to every token of each word.
+    ///
+    /// A word is encoded both alone and after a space, since the tokens of a word in
+    /// the middle of a sentence include the preceding space.
+    pub fn logit_bias<'a>(
+        &self,
+        words: impl IntoIterator<Item = &'a str>,
+        bias: f32,
+    ) -> HashMap<String, f32> {
+        words
+            .into_iter()
+            .flat_map(|word| {
+                let mut tokens = self.encode(word);
+                tokens.extend(self.encode(&format!(" {word}")));
+                tokens
+            })
+            .map(|token| (token.to_string(), bias))
+            .collect()
+    }
+}
+
+impl TokenCounter for Tokenizer {
+    fn count_message(&self, message: &ChatCompletionMessage) -> usize {
+        Tokenizer::count_message(self, message)
+    }
+
+    fn count_messages(&self, messages: &[ChatCompletionMessage]) -> usize {
+        Tokenizer::count_messages(self, messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::structured_output::ToolCallFunctionDefinition;
+    use crate::chat::{ChatCompletion, ChatCompletionMessageRole};
+    use serde_json::json;
+
+    #[test]
+    fn encode_and_decode() {
+        let cl100k = Tokenizer::new(Encoding::Cl100kBase);
+        assert_eq!(cl100k.encode("hello world"), [15339, 1917]);
+        let o200k = Tokenizer::for_model("gpt-4o-mini").unwrap();
+        assert_eq!(o200k.encoding(), Encoding::O200kBase);
+        assert_eq!(o200k.encode("hello world"), [24912, 2375]);
+        assert_eq!(o200k.decode(&[24912, 2375]).unwrap(), "hello world");
+        // Special tokens are plain text.
+        assert!(cl100k.count("<|endoftext|>") > 1);
+        assert_eq!(Encoding::for_model("text-davinci-003"), None);
+    }
+
+    #[test]
+    fn count_request() {
+        let tokenizer = Tokenizer::new(Encoding::Cl100kBase);
+        let messages = [ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some("hello world".into()),
+            ..Default::default()
+        }];
+        // 3 per message, 1 for the role, 2 for the content and 3 priming the reply.
+        assert_eq!(tokenizer.count_messages(&messages), 9);
+
+        let request = ChatCompletion::builder("gpt-4", messages.clone())
+            .build()
+            .unwrap();
+        assert_eq!(request.count_tokens(&tokenizer), 9);
+
+        let tools = [ChatCompletionTool::Function {
+            function: ToolCallFunctionDefinition {
+                name: "get_time".to_string(),
+                description: None,
+                parameters: Some(json!({ "type": "object" })),
+                strict: None,
+            },
+        }];
+        let request = ChatCompletion::builder("gpt-4", messages)
+            .tools(tools.clone())
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.count_tokens(&tokenizer),
+            9 + tokenizer.count_tools(&tools)
+        );
+        assert!(tokenizer.count_tools(&tools) > TOKENS_PER_TOOLS + TOKENS_PER_TOOL);
+    }
+
+    #[test]
+    fn logit_bias_from_words() {
+        let tokenizer = Tokenizer::new(Encoding::Cl100kBase);
+        let bias = tokenizer.logit_bias(["hello"], -100.0);
+        let mut tokens: Vec<_> = bias.keys().cloned().collect();
+        tokens.sort();
+        let mut expected: Vec<_> = tokenizer
+            .encode("hello")
+            .into_iter()
+            .chain(tokenizer.encode(" hello"))
+            .map(|token| token.to_string())
+            .collect();
+        expected.sort();
+        assert_eq!(tokens, expected);
+        assert!(bias.values().all(|bias| *bias == -100.0));
+    }
+}